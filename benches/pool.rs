@@ -0,0 +1,62 @@
+//! Compares opening tunnels over new connections with reusing the connections of
+//! a pool, over loopback. Run with `cargo bench --bench pool`.
+
+use quicssh_rs::{Client, Server, Tunnel};
+use std::error::Error;
+use std::future::Future;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const TUNNELS: u32 = 50;
+
+/// Returns the mean time to open a tunnel with `open` and get a byte echoed
+/// through it. Closing the tunnels isn't counted.
+async fn measure<F, T>(mut open: F) -> Result<Duration, Box<dyn Error>>
+where
+    F: FnMut() -> T,
+    T: Future<Output = Result<Tunnel, Box<dyn Error>>>,
+{
+    let mut total = Duration::ZERO;
+    for _ in 0..TUNNELS {
+        let started = Instant::now();
+        let mut tunnel = open().await?;
+        tunnel.write_all(b"x").await?;
+        let mut echoed = [0; 1];
+        tunnel.read_exact(&mut echoed).await?;
+        total += started.elapsed();
+        tunnel.close().await;
+    }
+    Ok(total / TUNNELS)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let backend = TcpListener::bind("127.0.0.1:0").await?;
+    let backend_addr = backend.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    let listen = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let server = Server::new(listen).route(move |_| backend_addr.clone());
+    tokio::spawn(async move {
+        let _ = server
+            .serve(std::future::pending())
+            .await
+            .map_err(|e| e.to_string());
+    });
+    let url = format!("quic://127.0.0.1:{}", listen.port());
+
+    let fresh = measure(|| Client::new(&url).unwrap().connect()).await?;
+    let pool = Client::new(&url)?.pool(1)?;
+    let pooled = measure(|| pool.connect()).await?;
+    println!("new connection per tunnel: {:>8.2?}", fresh);
+    println!("pooled connection:         {:>8.2?}", pooled);
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Measures the throughput of a tunnel in each direction over loopback, and the
+//! CPU time it takes, to compare changes to the relay. Run with
+//! `cargo bench --bench throughput`.
+//!
+//! Everything runs in one process, so the CPU time covers the client, the
+//! server and the backend. Expect a spread of 10% or more between runs on a
+//! shared machine; compare several runs of each build.
+
+use quicssh_rs::{Client, Server};
+use std::error::Error;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const BYTES: usize = 256 << 20;
+const CHUNK: usize = 64 << 10;
+/// Transfers per direction, the median of which is printed.
+const ROUNDS: usize = 5;
+
+/// Returns the CPU time used by the process so far, by the client, the server
+/// and the backend together.
+#[cfg(unix)]
+fn cpu_time() -> Duration {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+/// Starts a backend which discards what it reads if `upload`, else reads a byte
+/// then writes [`BYTES`] and closes.
+async fn start_backend(upload: bool) -> Result<String, Box<dyn Error>> {
+    let backend = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = backend.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            tokio::spawn(async move {
+                if upload {
+                    let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+                    return;
+                }
+                // unread data would make the close a reset
+                let _ = stream.read(&mut [0; 1]).await;
+                let chunk = vec![0; CHUNK];
+                for _ in 0..BYTES / CHUNK {
+                    if stream.write_all(&chunk).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Returns the throughput in MiB/s of sending [`BYTES`] through a tunnel to the
+/// backend if `upload`, else of receiving them from it, and the milliseconds of
+/// CPU time per MiB.
+async fn measure(upload: bool) -> Result<(f64, f64), Box<dyn Error>> {
+    let backend = start_backend(upload).await?;
+    let listen = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let server = Server::new(listen).route(move |_| backend.clone());
+    tokio::spawn(async move {
+        let _ = server
+            .serve(std::future::pending())
+            .await
+            .map_err(|e| e.to_string());
+    });
+    let url = format!("quic://127.0.0.1:{}", listen.port());
+    let mut tunnel = Client::new(&url)?.connect().await?;
+
+    let started = Instant::now();
+    let cpu_started = cpu_time();
+    if upload {
+        let chunk = vec![0; CHUNK];
+        for _ in 0..BYTES / CHUNK {
+            tunnel.write_all(&chunk).await?;
+        }
+        tunnel.shutdown().await?;
+        // the server finishes its side once the backend got everything
+        tunnel.read_to_end(&mut Vec::new()).await?;
+    } else {
+        // the server only sees the stream once something is sent on it
+        tunnel.write_all(b"x").await?;
+        let received = tokio::io::copy(&mut tunnel, &mut tokio::io::sink()).await?;
+        assert_eq!(received as usize, BYTES);
+    }
+    let elapsed = started.elapsed();
+    let cpu = cpu_time() - cpu_started;
+    tunnel.close().await;
+    let mib = BYTES as f64 / (1 << 20) as f64;
+    Ok((
+        mib / elapsed.as_secs_f64(),
+        cpu.as_secs_f64() * 1000.0 / mib,
+    ))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    for (name, upload) in [("upload", true), ("download", false)] {
+        let mut rounds = Vec::new();
+        for _ in 0..ROUNDS {
+            rounds.push(measure(upload).await?);
+        }
+        let median = |mut values: Vec<f64>| {
+            values.sort_by(f64::total_cmp);
+            values[values.len() / 2]
+        };
+        let throughput = median(rounds.iter().map(|round| round.0).collect());
+        let cpu = median(rounds.iter().map(|round| round.1).collect());
+        println!(
+            "{:<9} {:>7.1} MiB/s, {:>5.2} ms of CPU per MiB",
+            format!("{}:", name),
+            throughput,
+            cpu
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Records the versions of the QUIC and TLS libraries the binary is built
+//! against, for `--version`.
+
+use std::path::PathBuf;
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let manifest_path = manifest_dir.join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", manifest_path.display());
+    let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+    // built as a dependency, the lock file is the one of the workspace, next to
+    // its target directory
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let lock_path = std::iter::once(manifest_dir.as_path())
+        .chain(out_dir.ancestors())
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file());
+    let lock = match &lock_path {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.display());
+            std::fs::read_to_string(path).unwrap()
+        }
+        None => String::new(),
+    };
+
+    for name in ["quinn", "rustls"] {
+        let required = required_version(&manifest, name)
+            .unwrap_or_else(|| panic!("no version of {} in {}", name, manifest_path.display()));
+        // without a lock file, only the requirement is known
+        let version = locked_version(&lock, name, required)
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("^{}", required));
+        println!(
+            "cargo:rustc-env={}_VERSION={}",
+            name.to_uppercase(),
+            version
+        );
+    }
+}
+
+/// Finds the version requirement of a dependency in Cargo.toml, written either
+/// `name = "1.2.3"` or `name = { version = "1.2.3", ... }`.
+fn required_version<'a>(manifest: &'a str, name: &str) -> Option<&'a str> {
+    let line = manifest
+        .lines()
+        .find(|line| line.split('=').next().map(str::trim) == Some(name))?;
+    let value = line.split_once('=')?.1.trim();
+    let value = match value.strip_prefix('{') {
+        Some(table) => table.split_once("version")?.1.split_once('=')?.1.trim(),
+        None => value,
+    };
+    value.strip_prefix('"')?.split('"').next()
+}
+
+/// Finds the version of a package in Cargo.lock compatible with the requirement,
+/// as the lock file of a workspace may hold several.
+fn locked_version<'a>(lock: &'a str, name: &str, required: &str) -> Option<&'a str> {
+    let entry = format!("name = \"{}\"\nversion = \"", name);
+    lock.match_indices(&entry)
+        .filter_map(|(start, _)| lock[start + entry.len()..].split('"').next())
+        .find(|version| compatible(version, required))
+}
+
+/// Whether a version matches a caret requirement, by their leftmost non-zero
+/// component.
+fn compatible(version: &str, required: &str) -> bool {
+    let significant = |v: &str| {
+        let parts: Vec<&str> = v.split('.').collect();
+        let end = parts
+            .iter()
+            .position(|part| *part != "0")
+            .unwrap_or(parts.len() - 1);
+        parts[..=end].join(".")
+    };
+    let required = significant(required);
+    version == required || version.starts_with(&format!("{}.", required))
+}
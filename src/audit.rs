@@ -0,0 +1,172 @@
+//! The audit trail of `--audit-log`: a line per connection, appended when it
+//! ends, with the client, the ssh server it went to, how much it carried and how
+//! it ended.
+//!
+//! Each line goes out in a single write to a file opened for appending, so lines
+//! of concurrent connections don't interleave, and the file can be rotated with
+//! copytruncate.
+
+use chrono::{SecondsFormat, Utc};
+use log::error;
+use quinn::{Connection, ConnectionError, VarInt};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::close;
+use crate::traffic::Counters;
+
+/// The code and reason the server closed each connection with, by stable id,
+/// as quinn only tells that a connection was closed locally.
+static LOCAL_CLOSES: Mutex<BTreeMap<usize, (VarInt, &'static [u8])>> = Mutex::new(BTreeMap::new());
+
+/// Closes the connection, keeping the code and reason for its audit entry. The
+/// server closes connections through this, except on shutdown.
+pub fn close(connection: &Connection, code: VarInt, reason: &'static [u8]) {
+    LOCAL_CLOSES
+        .lock()
+        .unwrap()
+        .entry(connection.stable_id())
+        .or_insert((code, reason));
+    connection.close(code, reason);
+}
+
+/// Who ended a connection, with the close code and reason if any.
+enum Disposition {
+    Client(VarInt, String),
+    Server(VarInt, String),
+    Lost(String),
+}
+
+impl Disposition {
+    /// Takes how the connection ended, forgetting its local close.
+    fn take(connection: &Connection) -> Self {
+        let local = LOCAL_CLOSES.lock().unwrap().remove(&connection.stable_id());
+        match (connection.close_reason(), local) {
+            (Some(ConnectionError::ApplicationClosed(close)), _) => Disposition::Client(
+                close.error_code,
+                String::from_utf8_lossy(&close.reason).into_owned(),
+            ),
+            (Some(ConnectionError::LocallyClosed) | None, Some((code, reason))) => {
+                Disposition::Server(code, String::from_utf8_lossy(reason).into_owned())
+            }
+            // the endpoint closes the connections left at the end of a shutdown
+            (Some(ConnectionError::LocallyClosed) | None, None) => {
+                Disposition::Server(close::SERVER_SHUTDOWN, "server shutdown".into())
+            }
+            (Some(e), _) => Disposition::Lost(e.to_string()),
+        }
+    }
+
+    fn closed_by(&self) -> &'static str {
+        match self {
+            Disposition::Client(..) => "client",
+            Disposition::Server(..) => "server",
+            Disposition::Lost(_) => "lost",
+        }
+    }
+
+    fn code(&self) -> Option<u64> {
+        match self {
+            Disposition::Client(code, _) | Disposition::Server(code, _) => Some(code.into_inner()),
+            Disposition::Lost(_) => None,
+        }
+    }
+
+    fn reason(&self) -> &str {
+        match self {
+            Disposition::Client(_, reason)
+            | Disposition::Server(_, reason)
+            | Disposition::Lost(reason) => reason,
+        }
+    }
+}
+
+/// The audit log file, shared by the connections.
+pub struct AuditLog {
+    file: Mutex<File>,
+    json: bool,
+}
+
+impl AuditLog {
+    /// Opens the file for appending, creating it if needed. Writes JSON objects
+    /// instead of plain lines if `json`.
+    pub fn open(path: &Path, json: bool) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            json,
+        })
+    }
+
+    /// Appends the entry of a connection which has ended. `routed` is the ssh
+    /// server and the bytes relayed to and from it, `None` if the connection was
+    /// turned away before being routed.
+    fn record(
+        &self,
+        connection: &Connection,
+        disposition: Disposition,
+        sni: &str,
+        routed: Option<(&str, &Counters)>,
+        accepted: Instant,
+    ) {
+        let backend = routed.map(|(backend, _)| backend);
+        let (bytes_in, bytes_out) = routed.map_or((0, 0), |(_, counters)| counters.totals());
+        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let duration = accepted.elapsed().as_secs_f64();
+        let mut line = if self.json {
+            serde_json::json!({
+                "ts": ts,
+                "remote": connection.remote_address(),
+                "sni": sni,
+                "backend": backend,
+                "bytes_in": bytes_in,
+                "bytes_out": bytes_out,
+                "duration": duration,
+                "closed_by": disposition.closed_by(),
+                "code": disposition.code(),
+                "reason": disposition.reason(),
+            })
+            .to_string()
+        } else {
+            format!(
+                "{} remote={} sni={:?} backend={} bytes_in={} bytes_out={} duration={:.3}s closed_by={} code={} reason={:?}",
+                ts,
+                connection.remote_address(),
+                sni,
+                backend.unwrap_or("-"),
+                bytes_in,
+                bytes_out,
+                duration,
+                disposition.closed_by(),
+                disposition.code().map_or("-".into(), |code| code.to_string()),
+                disposition.reason(),
+            )
+        };
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("[server] write audit log error: {}", e);
+        }
+    }
+}
+
+/// Appends the entry of a connection which has ended to the audit log, if any,
+/// and forgets how the server closed it either way.
+pub fn finish(
+    log: Option<&AuditLog>,
+    connection: &Connection,
+    sni: &str,
+    routed: Option<(&str, &Counters)>,
+    accepted: Instant,
+) {
+    let disposition = Disposition::take(connection);
+    if let Some(log) = log {
+        log.record(connection, disposition, sni, routed, accepted);
+    }
+}
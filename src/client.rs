@@ -1,37 +1,545 @@
 // #![cfg(feature = "rustls")]
 
-use clap::Parser;
-use quinn::{ClientConfig, Endpoint, VarInt};
-use std::{error::Error, net::SocketAddr, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::{Buf, BufMut, BytesMut};
+use clap::builder::{NonEmptyStringValueParser, RangedU64ValueParser};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Parser};
+use quinn::{
+    ClientConfig, Connection, ConnectionError, Endpoint, EndpointConfig, ReadError, RecvStream,
+    SendStream, VarInt,
+};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::cell::Cell;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use std::{error::Error, sync::Arc, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::lookup_host;
+use tokio::task::JoinSet;
 
 #[cfg(not(windows))]
 use tokio::signal::unix::{signal, SignalKind};
 #[cfg(windows)]
 use tokio::signal::windows::ctrl_c;
-use url::Url;
+use url::{Host, Url};
+
+#[cfg(unix)]
+use crate::control::{self, Control};
+use crate::relay::Batch;
+use crate::sni::{self, SniMode};
+use crate::socket::PlainUdpSocket;
+use crate::socks5::{self, Socks5UdpSocket};
+use crate::throttle::{Throttle, Throttled};
+use crate::{close, congestion, datagram, stats};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn, Level};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(name = "client")]
 pub struct Opt {
     /// Server address
-    url: Url,
-    /// Client address
+    #[clap(required_unless_present = "proxy_command", value_parser = parse_url)]
+    url: Option<ServerUrl>,
+    /// Server host and port as ssh passes them to a ProxyCommand, instead of a URL,
+    /// e.g. `ProxyCommand quicssh-rs client --proxy-command %h %p`
+    #[clap(long = "proxy-command", num_args = 2, value_names = ["HOST", "PORT"], conflicts_with = "url")]
+    proxy_command: Vec<String>,
+    /// Server name to send in the handshake and verify the certificate for, instead
+    /// of the URL host, e.g. to pick a route of the server's conf by address
+    #[clap(long = "sni", value_parser = parse_server_name)]
+    sni: Option<String>,
+    /// What to send as SNI, which is not encrypted: the server name, nothing,
+    /// leaving the server to route by address, or --sni-decoy, with the server
+    /// name sent encrypted once connected
+    #[clap(long = "sni-mode", value_enum, default_value = "real")]
+    sni_mode: SniMode,
+    /// Server name to send as SNI with `--sni-mode fixed`, e.g. an innocuous host
+    /// served from the same address
+    #[clap(long = "sni-decoy", value_parser = parse_server_name)]
+    sni_decoy: Option<String>,
+    /// TOML file of defaults for the options below, which override it.
+    /// Defaults to ~/.config/quicssh/client.toml if it exists
+    #[clap(long = "conf", short = 'F')]
+    conf_path: Option<PathBuf>,
+    /// Client address. Defaults to $QUICSSH_BIND
     #[clap(long = "bind", short = 'b')]
     bind_addr: Option<SocketAddr>,
+    /// Use IPv4 only
+    #[clap(short = '4', long = "ipv4", conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// Use IPv6 only
+    #[clap(short = '6', long = "ipv6")]
+    ipv6: bool,
+    /// Network interface to send the packets from, Linux only
+    #[clap(long = "interface")]
+    interface: Option<String>,
+    /// SOCKS5 proxy to send the packets through, as host:port. It must support
+    /// UDP ASSOCIATE and accept clients without authentication
+    #[clap(long = "socks5")]
+    socks5: Option<String>,
+    /// Size in bytes to request for the UDP socket send buffer
+    #[clap(long = "udp-sndbuf")]
+    udp_sndbuf: Option<usize>,
+    /// Size in bytes to request for the UDP socket receive buffer
+    #[clap(long = "udp-rcvbuf")]
+    udp_rcvbuf: Option<usize>,
+    /// DSCP value to mark the packets with, e.g. 46 for expedited forwarding.
+    /// Disables ECN and GSO, which would override it
+    #[clap(long = "dscp", value_parser = RangedU64ValueParser::<u8>::new().range(0..=63))]
+    dscp: Option<u8>,
+    /// Idle timeout in seconds, 0 disables it
+    #[clap(long = "idle-timeout", default_value = "60")]
+    idle_timeout: u64,
+    /// Keep-alive interval in milliseconds, 0 disables it.
+    /// Must be shorter than the idle timeout, or the tunnel dies while idle
+    #[clap(long = "keep-alive", default_value = "1000")]
+    keep_alive: u64,
+    /// PEM file of CA certificates to verify the server with.
+    /// Without it or --pin the server certificate is not verified at all.
+    /// Defaults to $QUICSSH_CA
+    #[clap(long = "ca")]
+    ca_path: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate in hex, may be repeated.
+    /// Defaults to $QUICSSH_PIN, a comma separated list
+    #[clap(long = "pin", value_parser = parse_fingerprint, conflicts_with = "ca_path")]
+    pins: Vec<[u8; 32]>,
+    /// PEM file of the certificate chain to authenticate to the server with
+    #[clap(long = "client-cert", requires = "client_key_path")]
+    client_cert_path: Option<PathBuf>,
+    /// PEM file of the private key for --client-cert
+    #[clap(long = "client-key", requires = "client_cert_path")]
+    client_key_path: Option<PathBuf>,
+    /// Seconds to wait for the handshake in each connection attempt
+    #[clap(long = "connect-timeout", default_value = "10")]
+    connect_timeout: u64,
+    /// Times to retry connecting, with exponential backoff from 1 second
+    #[clap(long = "retries", default_value = "0")]
+    retries: u32,
+    /// Size in bytes of the copy buffers, two of them are used
+    #[clap(long = "buffer-size", default_value = "16384", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    buffer_size: usize,
+    /// Bytes per second to send to the server, on top of QUIC's own flow
+    /// control. Not applied in --datagram mode
+    #[clap(long = "rate-limit", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    rate_limit: Option<u64>,
+    /// Bytes the peer may send on the stream ahead of what was read, to size for
+    /// the bandwidth times the RTT of the link, e.g. 12500000 for 100 Mbit/s at
+    /// 100 ms [default: 1250000]
+    #[clap(long = "recv-window", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    recv_window: Option<u64>,
+    /// Bytes sent ahead of what the peer acknowledged, to size like --recv-window
+    /// [default: 10000000]
+    #[clap(long = "send-window", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    send_window: Option<u64>,
+    /// Disable path MTU discovery, keeping packets at --initial-mtu, e.g. on a
+    /// path that silently drops the larger probes
+    #[clap(long = "no-mtud")]
+    no_mtud: bool,
+    /// Send and receive the packets one by one, without the UDP segmentation
+    /// offload (GSO and GRO) some virtualized or older kernels mishandle. Also
+    /// stops ECN marking
+    #[clap(long = "no-gso")]
+    no_gso: bool,
+    /// UDP payload size in bytes to start with, before MTU discovery raises it
+    /// [default: 1200]
+    #[clap(long = "initial-mtu", value_parser = RangedU64ValueParser::<u16>::new().range(1200..=65527))]
+    initial_mtu: Option<u16>,
+    /// UDP payload size in bytes known to get through the path, which the MTU
+    /// never drops below, e.g. inside a VPN tunnel [default: 1200]
+    #[clap(long = "min-mtu", value_parser = RangedU64ValueParser::<u16>::new().range(1200..=65527))]
+    min_mtu: Option<u16>,
+    /// Congestion controller
+    #[clap(long = "cc", value_enum, default_value = "cubic")]
+    cc: congestion::Controller,
+    /// ALPN protocol identifier, must match the server's
+    #[clap(long = "alpn", default_value = "quicssh", value_parser = NonEmptyStringValueParser::new())]
+    alpn: String,
+    /// Forward over unreliable QUIC datagrams instead of a stream. Lossy, a single
+    /// lost packet breaks the ssh session; meant for experimentation only
+    #[clap(long = "datagram")]
+    datagram: bool,
+    /// Unix socket to share one connection between ssh sessions, like ssh's
+    /// ControlMaster: the first client becomes the master and the next ones
+    /// relay through it. Use a path per server, e.g. with ssh's %h and %p
+    #[clap(long = "control-socket")]
+    control_socket: Option<PathBuf>,
+    /// File to append the TLS secrets to, for Wireshark to decrypt a capture
+    /// with, e.g. "$SSLKEYLOGFILE". Anyone reading it can decrypt the session
+    #[clap(long = "keylog")]
+    keylog: Option<PathBuf>,
+    /// Log the RTT, congestion window, losses and MTU probes of the connection
+    /// every SECS seconds when they change, at the debug level
+    #[clap(long = "trace-transport", value_name = "SECS", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    trace_transport: Option<u64>,
+    #[clap(skip)]
+    sessions: Sessions,
+}
+
+/// The session tickets of the connections made with the options and their
+/// copies, for the next connections to the server to resume with 0-RTT. Only
+/// kept in memory.
+#[derive(Clone, Default)]
+struct Sessions(rustls::client::Resumption);
+
+impl std::fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Sessions")
+    }
+}
+
+/// A server URL, with the zone of a link-local IPv6 host kept aside as `Url`
+/// can't hold it, e.g. `eth0` in `quic://[fe80::1%eth0]:4433`.
+#[derive(Debug, Clone)]
+struct ServerUrl {
+    url: Url,
+    zone: Option<String>,
+}
+
+impl ServerUrl {
+    /// Returns the scope id to send to the host with, 0 if it has no zone.
+    fn scope_id(&self) -> Result<u32, String> {
+        let Some(zone) = &self.zone else {
+            return Ok(0);
+        };
+        if let Ok(index) = zone.parse() {
+            return Ok(index);
+        }
+        #[cfg(unix)]
+        if let Ok(name) = std::ffi::CString::new(zone.as_str()) {
+            // SAFETY: `name` is a valid NUL-terminated string for the duration of the call
+            let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            if index != 0 {
+                return Ok(index);
+            }
+        }
+        Err(format!("unknown network interface: {}", zone))
+    }
+}
+
+/// Parses a `quic://host[:port]` URL, rejecting the parts the tunnel would ignore.
+fn parse_url(s: &str) -> Result<ServerUrl, String> {
+    // take the zone out of the host, `Url` rejects it
+    let (s, zone) = match (s.find('['), s.find(']')) {
+        (Some(open), Some(close)) if open < close => match s[open..close].find('%') {
+            Some(percent) => (
+                format!("{}{}", &s[..open + percent], &s[close..]),
+                Some(s[open + percent + 1..close].to_string()),
+            ),
+            None => (s.to_string(), None),
+        },
+        _ => (s.to_string(), None),
+    };
+    if zone.as_deref() == Some("") {
+        return Err("IPv6 zone must not be empty".into());
+    }
+    let url = Url::parse(&s).map_err(|e| e.to_string())?;
+    if url.scheme() != "quic" {
+        return Err("URL scheme must be quic".into());
+    }
+    if url.host().is_none() {
+        return Err("quic URL must include a host".into());
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err("quic URL must not include credentials".into());
+    }
+    if !matches!(url.path(), "" | "/") || url.query().is_some() || url.fragment().is_some() {
+        return Err("quic URL must not include a path, query or fragment".into());
+    }
+    Ok(ServerUrl { url, zone })
+}
+
+/// Checks that a server name is a DNS name or an IP address, as rustls requires.
+pub(crate) fn parse_server_name(s: &str) -> Result<String, String> {
+    rustls::ServerName::try_from(s)
+        .map(|_| s.to_string())
+        .map_err(|e| format!("invalid server name: {}", e))
+}
+
+/// Parses a hex SHA-256 fingerprint, allowing `:` separators as printed by openssl.
+fn parse_fingerprint(s: &str) -> Result<[u8; 32], String> {
+    let hex: Vec<u8> = s.bytes().filter(|&c| c != b':').collect();
+    if hex.len() != 64 {
+        return Err("fingerprint must be 32 bytes of hex".into());
+    }
+    let mut fingerprint = [0; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|e| e.to_string())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|e| e.to_string())?;
+    }
+    Ok(fingerprint)
+}
+
+/// Defaults for the client options read from a TOML file, with the keys named
+/// after the flags, e.g. `idle-timeout = 30` or `pin = ["ab:cd:..."]`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ClientConf {
+    sni: Option<String>,
+    sni_mode: Option<SniMode>,
+    sni_decoy: Option<String>,
+    bind: Option<SocketAddr>,
+    ipv4: Option<bool>,
+    ipv6: Option<bool>,
+    interface: Option<String>,
+    socks5: Option<String>,
+    udp_sndbuf: Option<usize>,
+    udp_rcvbuf: Option<usize>,
+    dscp: Option<u8>,
+    idle_timeout: Option<u64>,
+    keep_alive: Option<u64>,
+    ca: Option<PathBuf>,
+    pin: Option<Vec<String>>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    connect_timeout: Option<u64>,
+    retries: Option<u32>,
+    buffer_size: Option<usize>,
+    rate_limit: Option<u64>,
+    recv_window: Option<u64>,
+    send_window: Option<u64>,
+    no_mtud: Option<bool>,
+    no_gso: Option<bool>,
+    initial_mtu: Option<u16>,
+    min_mtu: Option<u16>,
+    cc: Option<congestion::Controller>,
+    alpn: Option<String>,
+    datagram: Option<bool>,
+}
+
+impl ClientConf {
+    fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+/// `$XDG_CONFIG_HOME/quicssh/<name>`, falling back to `~/.config`.
+pub(crate) fn default_conf_path(name: &str) -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("quicssh").join(name))
+}
+
+/// Returns whether any of the options was given on the command line.
+fn given(matches: &ArgMatches, ids: &[&str]) -> bool {
+    ids.iter()
+        .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+}
+
+impl Opt {
+    /// Takes the options not given on the command line from the conf file, if any.
+    /// Options conflicting with one given on the command line are ignored too.
+    pub fn load_conf(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        let path = match (&self.conf_path, default_conf_path("client.toml")) {
+            (Some(path), _) => path.clone(),
+            (None, Some(path)) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let conf = ClientConf::load(&path)
+            .map_err(|e| format!("loading conf file {} failed: {}", path.display(), e))?;
+
+        let unset = |ids: &[&str]| !given(matches, ids);
+        macro_rules! merge {
+            ($($field:ident $(| $conflict:ident)* = $value:expr;)*) => {$(
+                if let (true, Some(value)) = (
+                    unset(&[stringify!($field) $(, stringify!($conflict))*]),
+                    $value,
+                ) {
+                    self.$field = value;
+                }
+            )*};
+        }
+        let pins = conf
+            .pin
+            .map(|pins| pins.iter().map(|pin| parse_fingerprint(pin)).collect())
+            .transpose()
+            .map_err(|e| format!("invalid pin in conf file {}: {}", path.display(), e))?;
+        let sni = conf
+            .sni
+            .map(|sni| parse_server_name(&sni))
+            .transpose()
+            .map_err(|e| format!("invalid sni in conf file {}: {}", path.display(), e))?;
+        let sni_decoy = conf
+            .sni_decoy
+            .map(|sni| parse_server_name(&sni))
+            .transpose()
+            .map_err(|e| format!("invalid sni-decoy in conf file {}: {}", path.display(), e))?;
+        merge! {
+            sni = sni.map(Some);
+            sni_mode = conf.sni_mode;
+            sni_decoy = sni_decoy.map(Some);
+            bind_addr = conf.bind.map(Some);
+            ipv4 | ipv6 = conf.ipv4;
+            ipv6 | ipv4 = conf.ipv6;
+            interface = conf.interface.map(Some);
+            socks5 = conf.socks5.map(Some);
+            udp_sndbuf = conf.udp_sndbuf.map(Some);
+            udp_rcvbuf = conf.udp_rcvbuf.map(Some);
+            dscp = conf.dscp.map(Some);
+            idle_timeout = conf.idle_timeout;
+            keep_alive = conf.keep_alive;
+            ca_path | pins = conf.ca.map(Some);
+            pins | ca_path = pins;
+            client_cert_path | client_key_path = conf.client_cert.map(Some);
+            client_key_path | client_cert_path = conf.client_key.map(Some);
+            connect_timeout = conf.connect_timeout;
+            retries = conf.retries;
+            buffer_size = conf.buffer_size;
+            rate_limit = conf.rate_limit.map(Some);
+            recv_window = conf.recv_window.map(Some);
+            send_window = conf.send_window.map(Some);
+            no_mtud = conf.no_mtud;
+            no_gso = conf.no_gso;
+            initial_mtu = conf.initial_mtu.map(Some);
+            min_mtu = conf.min_mtu.map(Some);
+            cc = conf.cc;
+            alpn = conf.alpn;
+            datagram = conf.datagram;
+        }
+        Ok(())
+    }
+
+    /// Takes the options not given on the command line from the `QUICSSH_CA`,
+    /// `QUICSSH_PIN` and `QUICSSH_BIND` environment variables, over the conf file.
+    pub fn load_env(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        // either variable replaces both options of the conf file, as they conflict
+        let (ca, pin) = (var("QUICSSH_CA"), var("QUICSSH_PIN"));
+        if (ca.is_some() || pin.is_some()) && !given(matches, &["ca_path", "pins"]) {
+            self.ca_path = ca.map(PathBuf::from);
+            self.pins = match pin {
+                Some(pin) => pin
+                    .split(',')
+                    .map(parse_fingerprint)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("invalid QUICSSH_PIN: {}", e))?,
+                None => Vec::new(),
+            };
+        }
+        if let Some(bind) = var("QUICSSH_BIND") {
+            if !given(matches, &["bind_addr"]) {
+                self.bind_addr = Some(
+                    bind.parse()
+                        .map_err(|e| format!("invalid QUICSSH_BIND: {}", e))?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the constraints between options which clap can't express, or
+    /// can't check for the options taken from the conf file.
+    pub fn validate(&self) -> Result<(), String> {
+        self.url()?;
+        if self.ipv4 && self.ipv6 {
+            return Err("-4 and -6 can't be used together".into());
+        }
+        if self.ca_path.is_some() && !self.pins.is_empty() {
+            return Err("--ca and --pin can't be used together".into());
+        }
+        if self.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err("dscp must be 0 to 63".into());
+        }
+        // the conf file skips the range checks of the flags
+        for mtu in [self.initial_mtu, self.min_mtu].into_iter().flatten() {
+            if !(1200..=65527).contains(&mtu) {
+                return Err(format!("MTU {} must be 1200 to 65527", mtu));
+            }
+        }
+        if let (Some(min_mtu), Some(initial_mtu)) = (self.min_mtu, self.initial_mtu) {
+            if min_mtu > initial_mtu {
+                return Err(format!(
+                    "--min-mtu {} must not exceed --initial-mtu {}",
+                    min_mtu, initial_mtu
+                ));
+            }
+        }
+        if self.buffer_size == 0 {
+            return Err("buffer size must be at least 1".into());
+        }
+        if self.control_socket.is_some() {
+            if cfg!(not(unix)) {
+                return Err("--control-socket is only supported on unix".into());
+            }
+            if self.datagram {
+                return Err("--control-socket can't be used in the datagram mode".into());
+            }
+        }
+        if self.rate_limit == Some(0) {
+            return Err("rate limit must be at least 1".into());
+        }
+        if self.alpn.is_empty() {
+            return Err("alpn must not be empty".into());
+        }
+        if self.idle_timeout != 0
+            && self.keep_alive != 0
+            && Duration::from_millis(self.keep_alive) >= Duration::from_secs(self.idle_timeout)
+        {
+            return Err(format!(
+                "keep-alive interval ({}ms) must be shorter than idle timeout ({}s)",
+                self.keep_alive, self.idle_timeout
+            ));
+        }
+        if let (Some(bind_addr), Some(ipv6)) = (self.bind_addr, self.forced_family()) {
+            if bind_addr.is_ipv6() != ipv6 {
+                return Err("bind address family conflicts with -4/-6".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the server URL, built from the host and port in --proxy-command mode.
+    fn url(&self) -> Result<ServerUrl, String> {
+        match (&self.url, &self.proxy_command[..]) {
+            (Some(url), _) => Ok(url.clone()),
+            (None, [host, port]) => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| format!("invalid port: {}", port))?;
+                // ssh passes IPv6 literals without brackets, with the zone if any
+                let (ip, zone) = match host.split_once('%') {
+                    Some((ip, zone)) => (ip, format!("%{}", zone)),
+                    None => (host.as_str(), String::new()),
+                };
+                let host = match ip.parse::<Ipv6Addr>() {
+                    Ok(ip) => format!("[{}{}]", ip, zone),
+                    Err(_) => host.clone(),
+                };
+                parse_url(&format!("quic://{}:{}", host, port))
+                    .map_err(|e| format!("invalid host {}: {}", host, e))
+            }
+            _ => Err("either a URL or --proxy-command is required".into()),
+        }
+    }
+
+    /// Returns whether only IPv6 (`true`) or only IPv4 (`false`) may be used,
+    /// if either is forced by -4/-6 or the bind address.
+    fn forced_family(&self) -> Option<bool> {
+        match (self.ipv4, self.ipv6) {
+            (true, _) => Some(false),
+            (_, true) => Some(true),
+            _ => self.bind_addr.as_ref().map(SocketAddr::is_ipv6),
+        }
+    }
 }
 
 /// Enables MTUD if supported by the operating system
-#[cfg(not(any(windows, os = "linux")))]
+#[cfg(not(any(windows, target_os = "linux")))]
 pub fn enable_mtud_if_supported() -> quinn::TransportConfig {
     quinn::TransportConfig::default()
 }
 
 /// Enables MTUD if supported by the operating system
-#[cfg(any(windows, os = "linux"))]
+#[cfg(any(windows, target_os = "linux"))]
 pub fn enable_mtud_if_supported() -> quinn::TransportConfig {
     let mut transport_config = quinn::TransportConfig::default();
     transport_config.mtu_discovery_config(Some(quinn::MtuDiscoveryConfig::default()));
@@ -60,137 +568,843 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-fn configure_client() -> Result<ClientConfig, Box<dyn Error>> {
-    let crypto = rustls::ClientConfig::builder()
+/// Accepts the server certificate only if its SHA-256 fingerprint matches one of the pins.
+struct PinnedServerVerification {
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedServerVerification {
+    fn new(pins: Vec<[u8; 32]>) -> Arc<Self> {
+        Arc::new(Self { pins })
+    }
+}
+
+impl rustls::client::ServerCertVerifier for PinnedServerVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        // check every pin to keep the comparison constant-time
+        let matched = self.pins.iter().fold(false, |matched, pin| {
+            ring::constant_time::verify_slices_are_equal(digest.as_ref(), pin).is_ok() | matched
+        });
+        if matched {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate fingerprint doesn't match any pin".into(),
+            ))
+        }
+    }
+}
+
+/// Verifies the server certificate for the server name instead of the decoy sent
+/// in the handshake with `--sni-mode fixed`.
+struct DecoyVerification {
+    inner: Arc<dyn rustls::client::ServerCertVerifier>,
+    server_name: rustls::ServerName,
+}
+
+impl rustls::client::ServerCertVerifier for DecoyVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// `server_name` is the name the server certificate is verified for.
+fn configure_client(options: &Opt, server_name: &str) -> Result<ClientConfig, Box<dyn Error>> {
+    let verifier: Arc<dyn rustls::client::ServerCertVerifier> = match &options.ca_path {
+        None if !options.pins.is_empty() => PinnedServerVerification::new(options.pins.clone()),
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in crate::pem::load_certs(path)? {
+                roots.add(&cert)?;
+            }
+            Arc::new(rustls::client::WebPkiVerifier::new(roots, None))
+        }
+        None => {
+            warn!("[client] no CA or pin given, the server certificate will not be verified");
+            SkipServerVerification::new()
+        }
+    };
+    let verifier = match options.sni_mode {
+        SniMode::Fixed => Arc::new(DecoyVerification {
+            inner: verifier,
+            server_name: rustls::ServerName::try_from(server_name)?,
+        }),
+        SniMode::Real | SniMode::None => verifier,
+    };
+    let builder = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(verifier);
+    let mut crypto = match (&options.client_cert_path, &options.client_key_path) {
+        (Some(cert_path), Some(key_path)) => builder.with_client_auth_cert(
+            crate::pem::load_certs(cert_path)?,
+            crate::pem::load_private_key(key_path)?,
+        )?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("--client-cert and --client-key must be given together".into()),
+    };
+    // send the first flight as 0-RTT data when resuming a session, with a
+    // ticket from an earlier connection made with these options
+    crypto.resumption = options.sessions.0.clone();
+    crypto.enable_early_data = true;
+    crypto.enable_sni = options.sni_mode != SniMode::None;
+    if let Some(path) = &options.keylog {
+        crypto.key_log = crate::keylog::open(path, "client")
+            .map_err(|e| format!("opening key log {} failed: {}", path.display(), e))?;
+    }
+    crypto.alpn_protocols = vec![if options.datagram {
+        datagram::alpn(&options.alpn)
+    } else if options.sni_mode == SniMode::Fixed {
+        sni::alpn(&options.alpn)
+    } else {
+        options.alpn.as_bytes().to_vec()
+    }];
 
     let mut client_config = ClientConfig::new(Arc::new(crypto));
     let mut transport_config = enable_mtud_if_supported();
-    transport_config.max_idle_timeout(Some(VarInt::from_u32(60_000).into()));
-    transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(1)));
+    transport_config.max_idle_timeout(match options.idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs).try_into()?),
+    });
+    transport_config.keep_alive_interval(match options.keep_alive {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    });
+    transport_config
+        .datagram_receive_buffer_size(options.datagram.then_some(datagram::RECEIVE_BUFFER_SIZE));
+    if let Some(window) = options.recv_window {
+        transport_config.stream_receive_window(VarInt::from_u64(window)?);
+        transport_config.receive_window(VarInt::from_u64(window)?);
+    }
+    if let Some(window) = options.send_window {
+        transport_config.send_window(window);
+    }
+    if options.no_mtud {
+        transport_config.mtu_discovery_config(None);
+    }
+    if let Some(mtu) = options.initial_mtu {
+        transport_config.initial_mtu(mtu);
+    }
+    if let Some(mtu) = options.min_mtu {
+        transport_config.min_mtu(mtu);
+    }
+    options.cc.apply(&mut transport_config);
     client_config.transport_config(Arc::new(transport_config));
 
     Ok(client_config)
 }
 
+/// Binds the socket to the network interface so that packets leave through it
+/// regardless of the routing table.
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_to_interface(socket: &Socket, interface: &str) -> Result<(), Box<dyn Error>> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| format!("binding to interface {} failed: {}", interface, e).into())
+}
+
+/// Binds the socket to the network interface so that packets leave through it
+/// regardless of the routing table.
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_to_interface(_socket: &Socket, _interface: &str) -> Result<(), Box<dyn Error>> {
+    Err("binding to an interface is unsupported on this platform".into())
+}
+
+/// Sets the traffic class of the packets, the IPv4 ToS or IPv6 traffic class.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn set_traffic_class(socket: &Socket, ipv6: bool, class: u32) -> io::Result<()> {
+    if ipv6 {
+        socket.set_tclass_v6(class)
+    } else {
+        socket.set_tos(class)
+    }
+}
+
+/// Sets the traffic class of the packets, the IPv4 ToS or IPv6 traffic class.
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn set_traffic_class(_socket: &Socket, _ipv6: bool, _class: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Creates the UDP socket for a client endpoint.
+fn make_client_socket(bind_addr: SocketAddr, options: &Opt) -> Result<UdpSocket, Box<dyn Error>> {
+    let socket = Socket::new(
+        Domain::for_address(bind_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    // candidates are split by family, so behave as Windows does by default
+    // rather than depending on the platform's dual-stack setting
+    if bind_addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(interface) = &options.interface {
+        bind_to_interface(&socket, interface)?;
+    }
+    if let Some(dscp) = options.dscp {
+        // DSCP is the upper six bits, the lower two are ECN
+        if let Err(e) = set_traffic_class(&socket, bind_addr.is_ipv6(), u32::from(dscp) << 2) {
+            warn!("[client] can't set DSCP, sending unmarked packets: {}", e);
+        }
+    }
+    // the kernel may clamp the sizes, so log what it actually granted
+    if let Some(size) = options.udp_sndbuf {
+        socket.set_send_buffer_size(size)?;
+        info!(
+            "[client] UDP send buffer: {} bytes requested, {} granted",
+            size,
+            socket.send_buffer_size()?
+        );
+    }
+    if let Some(size) = options.udp_rcvbuf {
+        socket.set_recv_buffer_size(size)?;
+        info!(
+            "[client] UDP receive buffer: {} bytes requested, {} granted",
+            size,
+            socket.recv_buffer_size()?
+        );
+    }
+    socket.bind(&bind_addr.into())?;
+    Ok(socket.into())
+}
+
+/// Creates the socket to bind, falling back to a plain bind when there are no
+/// socket options to apply.
+///
+/// Errors name the address family, as a sandbox or a kernel without IPv6 may
+/// refuse sockets of one family only.
+fn bind_client_socket(bind_addr: SocketAddr, options: &Opt) -> Result<UdpSocket, Box<dyn Error>> {
+    let family = if bind_addr.is_ipv6() { "IPv6" } else { "IPv4" };
+    let failed = |e: &dyn std::fmt::Display| {
+        io::Error::other(format!(
+            "creating {} UDP socket bound to {} failed: {}",
+            family, bind_addr, e
+        ))
+    };
+    // without options to apply, any socket will do if socket2 fails in a sandbox
+    let tuned = options.interface.is_some()
+        || options.udp_sndbuf.is_some()
+        || options.udp_rcvbuf.is_some()
+        || options.dscp.is_some();
+    let socket = match make_client_socket(bind_addr, options) {
+        Ok(socket) => {
+            debug!("[client] created socket bound to: {}", bind_addr);
+            socket
+        }
+        Err(e) if !tuned => {
+            debug!(
+                "[client] creating socket failed, falling back to a plain bind: {}",
+                e
+            );
+            UdpSocket::bind(bind_addr).map_err(|e| failed(&e))?
+        }
+        Err(e) => return Err(failed(&e).into()),
+    };
+    Ok(socket)
+}
+
 /// Constructs a QUIC endpoint configured for use a client only.
 ///
 /// ## Args
 ///
-/// - server_certs: list of trusted certificates.
+/// - bind_addr: local address to bind.
+/// - options: options for the socket.
+/// - client_cfg: configuration made by `configure_client`.
 #[allow(unused)]
-pub fn make_client_endpoint(bind_addr: SocketAddr) -> Result<Endpoint, Box<dyn Error>> {
-    let client_cfg = configure_client()?;
-    let mut endpoint = Endpoint::client(bind_addr)?;
+pub fn make_client_endpoint(
+    bind_addr: SocketAddr,
+    options: &Opt,
+    client_cfg: ClientConfig,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let runtime = quinn::default_runtime().ok_or("no async runtime found")?;
+    let socket = bind_client_socket(bind_addr, options)?;
+    let mut endpoint = if options.dscp.is_some() || options.no_gso {
+        let socket = PlainUdpSocket::new(socket)?;
+        Endpoint::new_with_abstract_socket(EndpointConfig::default(), None, socket, runtime)?
+    } else {
+        Endpoint::new(EndpointConfig::default(), None, socket, runtime)?
+    };
     endpoint.set_default_client_config(client_cfg);
     Ok(endpoint)
 }
 
-#[tokio::main]
-pub async fn run(options: Opt) -> Result<(), Box<dyn Error>> {
-    let url = options.url;
-    if url.scheme() != "quic" {
-        return Err("URL scheme must be quic".into());
+/// Constructs a client endpoint sending its packets through the relay of a
+/// SOCKS5 proxy.
+fn make_socks5_endpoint(
+    association: socks5::Association,
+    options: &Opt,
+    client_cfg: ClientConfig,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let runtime = quinn::default_runtime().ok_or("no async runtime found")?;
+    let bind_addr = options
+        .bind_addr
+        .unwrap_or(unspecified_for(&association.relay()));
+    let socket = Socks5UdpSocket::new(bind_client_socket(bind_addr, options)?, association)?;
+    let mut endpoint =
+        Endpoint::new_with_abstract_socket(EndpointConfig::default(), None, socket, runtime)?;
+    endpoint.set_default_client_config(client_cfg);
+    Ok(endpoint)
+}
+
+/// Delay before starting the next connection attempt while the previous one is
+/// still in progress, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Returns the unspecified address of the same family as the remote, to bind to.
+fn unspecified_for(remote: &SocketAddr) -> SocketAddr {
+    use std::net::{IpAddr::*, Ipv4Addr, Ipv6Addr};
+    if remote.is_ipv6() {
+        SocketAddr::new(V6(Ipv6Addr::UNSPECIFIED), 0)
+    } else {
+        SocketAddr::new(V4(Ipv4Addr::UNSPECIFIED), 0)
+    }
+}
+
+/// Pairs each resolved address with an endpoint of its family, alternating the
+/// families starting from the first resolved one (RFC 8305 section 4).
+fn make_candidates(
+    addrs: &[SocketAddr],
+    options: &Opt,
+    client_cfg: &ClientConfig,
+    association: Option<socks5::Association>,
+) -> Result<Vec<(Endpoint, SocketAddr)>, Box<dyn Error>> {
+    let addrs: Vec<SocketAddr> = match options.forced_family() {
+        Some(ipv6) => addrs
+            .iter()
+            .filter(|addr| addr.is_ipv6() == ipv6)
+            .copied()
+            .collect(),
+        None => addrs.to_vec(),
+    };
+    let Some(first_is_ipv6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return Err(match options.forced_family() {
+            Some(true) => "no IPv6 address to connect to",
+            _ => "no IPv4 address to connect to",
+        }
+        .into());
+    };
+
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    let (primary, secondary): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let (mut primary, mut secondary) = (primary.into_iter(), secondary.into_iter());
+    loop {
+        match (primary.next(), secondary.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
     }
 
-    // Currently `url` crate doesn't recognize quic as scheme (see socket_addrs()), so we can set default port using argument. In future if quic default port is added (as 80 or 443, likely), we will fail to connect to proper port. Ideally we should define own scheme. (ex. "qsrs://" abbr of quicssh-rs)
-    let sock_list = url
-        .socket_addrs(|| Some(4433))
-        .map_err(|_| "Couldn't resolve to any address")?;
+    // the relay reaches either family, so one endpoint through it serves them all
+    if let Some(association) = association {
+        let endpoint = make_socks5_endpoint(association, options, client_cfg.clone())?;
+        return Ok(interleaved
+            .into_iter()
+            .map(|remote| (endpoint.clone(), remote))
+            .collect());
+    }
+
+    // share one endpoint among the candidates of each family. A family whose
+    // socket can't be created, e.g. IPv6 on a kernel without it, is left out
+    // as long as the other family has candidates
+    let mut v4_endpoint: Option<Result<Endpoint, String>> = None;
+    let mut v6_endpoint: Option<Result<Endpoint, String>> = None;
+    let mut candidates = Vec::with_capacity(interleaved.len());
+    for remote in interleaved {
+        let slot = if remote.is_ipv6() {
+            &mut v6_endpoint
+        } else {
+            &mut v4_endpoint
+        };
+        let endpoint = slot.get_or_insert_with(|| {
+            make_client_endpoint(
+                options.bind_addr.unwrap_or(unspecified_for(&remote)),
+                options,
+                client_cfg.clone(),
+            )
+            .map_err(|e| e.to_string())
+        });
+        if let Ok(endpoint) = endpoint {
+            candidates.push((endpoint.clone(), remote));
+        }
+    }
+    match (v4_endpoint, v6_endpoint) {
+        (Some(Err(e)), Some(Ok(_))) => {
+            warn!("[client] {}, falling back to the IPv6 addresses", e)
+        }
+        (Some(Ok(_)), Some(Err(e))) => {
+            warn!("[client] {}, falling back to the IPv4 addresses", e)
+        }
+        (Some(Err(e)), _) | (_, Some(Err(e))) => return Err(e.into()),
+        _ => (),
+    }
+    Ok(candidates)
+}
 
-    // Currently we only use the first addr. The other addrs should be fallbacks of the connection, but not implemented now.
-    let remote = sock_list[0];
-    let sni = url.host_str().unwrap_or("THIS_HOSTNAME_SHOULD_NOT_BE_USED");
+/// Transport error code of the TLS alert sent when the peer doesn't support any
+/// of the offered ALPN protocols, crypto errors being 0x100 plus the alert.
+const NO_APPLICATION_PROTOCOL: u64 = 0x100 + 120;
 
-    info!("[client] Connecting to: {} <- {}", remote, sni);
+/// Races connection attempts to the candidates à la Happy Eyeballs (RFC 8305),
+/// starting each one after the previous fails or `CONNECTION_ATTEMPT_DELAY` passes.
+/// The first one to complete the handshake wins and the rest are cancelled.
+async fn connect_happy_eyeballs(
+    candidates: &[(Endpoint, SocketAddr)],
+    sni: &str,
+) -> Result<(Endpoint, Connection), Box<dyn Error>> {
+    let mut candidates = candidates.iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = String::from("no address to connect to");
+    loop {
+        let has_next = match candidates.next() {
+            Some((endpoint, remote)) => {
+                debug!("[client] trying to connect to: {}", remote);
+                match endpoint.connect(*remote, sni).map(|c| c.into_0rtt()) {
+                    // a ticket from an earlier connection lets us skip the handshake RTT
+                    Ok(Ok((connection, accepted))) => {
+                        info!("[client] resuming the session with 0-RTT to: {}", remote);
+                        tokio::spawn(async move {
+                            if accepted.await {
+                                info!("[client] 0-RTT data accepted by the server");
+                            } else {
+                                warn!("[client] 0-RTT data rejected by the server");
+                            }
+                        });
+                        return Ok((endpoint.clone(), connection));
+                    }
+                    Ok(Err(connecting)) => {
+                        let endpoint = endpoint.clone();
+                        let remote = *remote;
+                        attempts.spawn(async move { (endpoint, remote, connecting.await) });
+                    }
+                    Err(e) => {
+                        last_error = format!("failed to initiate connection to {}: {}", remote, e);
+                        warn!("[client] {}", last_error);
+                    }
+                }
+                true
+            }
+            None if attempts.is_empty() => return Err(last_error.into()),
+            None => false,
+        };
+        if attempts.is_empty() {
+            continue;
+        }
 
-    let endpoint = make_client_endpoint(match options.bind_addr {
-        Some(local) => local,
-        None => {
-            use std::net::{IpAddr::*, Ipv4Addr, Ipv6Addr};
-            if remote.is_ipv6() {
-                SocketAddr::new(V6(Ipv6Addr::UNSPECIFIED), 0)
+        let delay = async {
+            if has_next {
+                tokio::time::sleep(CONNECTION_ATTEMPT_DELAY).await
             } else {
-                SocketAddr::new(V4(Ipv4Addr::UNSPECIFIED), 0)
+                std::future::pending().await
             }
+        };
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok((endpoint, remote, Ok(connection))) => {
+                    info!("[client] no 0-RTT ticket, completed a 1-RTT handshake with: {}", remote);
+                    return Ok((endpoint, connection));
+                }
+                Ok((_, remote, Err(ConnectionError::ConnectionClosed(close))))
+                    if u64::from(close.error_code) == NO_APPLICATION_PROTOCOL =>
+                {
+                    last_error = format!(
+                        "{} rejected the ALPN, check --alpn and --datagram match the server",
+                        remote
+                    );
+                    warn!("[client] {}", last_error);
+                }
+                Ok((_, remote, Err(e))) => {
+                    last_error = format!("handshake with {} failed: {}", remote, e);
+                    warn!("[client] {}", last_error);
+                }
+                Err(e) => last_error = e.to_string(),
+            },
+            _ = delay => (),
+        }
+    }
+}
+
+/// Connects to the server, retrying with exponential backoff on timeout or handshake error.
+async fn connect_with_retries(
+    candidates: &[(Endpoint, SocketAddr)],
+    sni: &str,
+    options: &Opt,
+) -> Result<(Endpoint, Connection), Box<dyn Error>> {
+    let connect_timeout = Duration::from_secs(options.connect_timeout);
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            info!("[client] retrying in {}s", backoff.as_secs());
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        info!(
+            "[client] connection attempt {}/{}",
+            attempt + 1,
+            options.retries + 1
+        );
+        match tokio::time::timeout(connect_timeout, connect_happy_eyeballs(candidates, sni)).await {
+            Ok(Ok(connected)) => return Ok(connected),
+            Ok(Err(e)) => warn!("[client] connection attempt failed: {}", e),
+            Err(_) => warn!("[client] connection attempt timed out"),
+        }
+    }
+    Err(format!("failed to connect after {} attempts", options.retries + 1).into())
+}
+
+/// Resolves the server address and connects to it, racing the families and
+/// retrying as the options say.
+async fn connect(options: &Opt) -> Result<(Endpoint, Connection), Box<dyn Error>> {
+    let server_url = options.url()?;
+    let url = &server_url.url;
+
+    // Currently `url` crate doesn't recognize quic as scheme (see port_or_known_default()), so we can set default port by ourselves. In future if quic default port is added (as 80 or 443, likely), `port()` still returns None for it so we will keep using 4433. Ideally we should define own scheme. (ex. "qsrs://" abbr of quicssh-rs)
+    let port = url.port().unwrap_or(4433);
+    // resolve asynchronously so that a slow resolver doesn't block the runtime
+    let sock_list: Vec<SocketAddr> = match url.host() {
+        Some(Host::Domain(domain)) => lookup_host((domain, port))
+            .await
+            .map_err(|e| format!("Couldn't resolve {}: {}", domain, e))?
+            .collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => {
+            let scope_id = server_url.scope_id()?;
+            vec![SocketAddrV6::new(ip, port, 0, scope_id).into()]
         }
-    })?;
+        None => return Err("Couldn't resolve to any address".into()),
+    };
+
+    // the host of an IPv6 URL is bracketed, which isn't a valid server name
+    let sni = &match (&options.sni, url.host()) {
+        (Some(sni), _) => sni.clone(),
+        (None, Some(Host::Ipv6(ip))) => ip.to_string(),
+        _ => url
+            .host_str()
+            .unwrap_or("THIS_HOSTNAME_SHOULD_NOT_BE_USED")
+            .to_string(),
+    };
+
+    let handshake_name = &match (options.sni_mode, &options.sni_decoy) {
+        (SniMode::Fixed, _) if options.datagram => {
+            return Err("--sni-mode fixed doesn't work with --datagram".into())
+        }
+        (SniMode::Fixed, Some(decoy)) => decoy.clone(),
+        (SniMode::Fixed, None) => return Err("--sni-mode fixed needs --sni-decoy".into()),
+        (SniMode::Real | SniMode::None, _) => sni.clone(),
+    };
+
+    info!("[client] Connecting to: {:?} <- {}", sock_list, sni);
+    match options.sni_mode {
+        SniMode::Real => (),
+        SniMode::None => debug!("[client] sending no SNI"),
+        SniMode::Fixed => debug!("[client] sending decoy SNI: {}", handshake_name),
+    }
+
+    let association = match &options.socks5 {
+        Some(proxy) => {
+            let association = socks5::associate(proxy)
+                .await
+                .map_err(|e| format!("SOCKS5 proxy {} failed: {}", proxy, e))?;
+            info!(
+                "[client] relaying through SOCKS5 proxy {} at: {}",
+                proxy,
+                association.relay()
+            );
+            Some(association)
+        }
+        None => None,
+    };
+
+    let client_cfg = configure_client(options, sni)?;
+    let candidates = make_candidates(&sock_list, options, &client_cfg, association)?;
     // connect to server
-    let connection = endpoint.connect(remote, sni).unwrap().await.unwrap();
+    let (endpoint, connection) = connect_with_retries(&candidates, handshake_name, options).await?;
+    drop(candidates);
+    if options.sni_mode == SniMode::Fixed {
+        sni::send_route(&connection, sni)
+            .await
+            .map_err(|e| format!("sending the route header failed: {}", e))?;
+    }
     info!(
+        remote:% = connection.remote_address(), sni:% = sni;
         "[client] Connected to: {} <- {}",
         connection.remote_address(),
         sni
     );
+    let alpn = stats::alpn(&connection);
+    info!(remote:% = connection.remote_address(), alpn:% = alpn; "[client] negotiated ALPN: {}", alpn);
+    Ok((endpoint, connection))
+}
+
+/// Runs the client on a runtime of its own, see `run_inner`.
+pub fn run(options: Opt) -> Result<ExitCode, Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let exit_code = runtime.block_on(run_inner(options));
+    // stdin is read on a blocking thread which can't be interrupted, so waiting
+    // for it would keep the client alive until ssh closes stdin
+    runtime.shutdown_background();
+    exit_code
+}
+
+/// Connects to the server and relays stdin and stdout over the connection until
+/// either side closes it, returning the exit code for the close.
+pub async fn run_inner(options: Opt) -> Result<ExitCode, Box<dyn Error>> {
+    #[cfg(unix)]
+    let master = match &options.control_socket {
+        Some(path) => match control::open(path)
+            .await
+            .map_err(|e| format!("control socket {} failed: {}", path.display(), e))?
+        {
+            Control::Attach(stream) => {
+                info!("[client] attached to the master on: {}", path.display());
+                control::relay_attached(stream).await?;
+                info!("[client] exit client");
+                return Ok(ExitCode::SUCCESS);
+            }
+            Control::Master(master) => Some(master),
+        },
+        None => None,
+    };
+
+    let (endpoint, connection) = connect(&options).await?;
+
+    if options.datagram && !datagram::negotiated(&connection, &options.alpn) {
+        connection.close(close::NORMAL, b"datagram mode not supported");
+        endpoint.wait_idle().await;
+        return Err("server doesn't support the datagram mode".into());
+    }
 
-    let (mut send, mut recv) = connection
-        .open_bi()
-        .await
-        .map_err(|e| format!("failed to open stream: {}", e))?;
+    if let Some(secs) = options.trace_transport {
+        let interval = Duration::from_secs(secs);
+        tokio::spawn(stats::trace(connection.clone(), "client", interval));
+    }
+
+    #[cfg(not(windows))]
+    tokio::spawn(rebind_on_user_signal(
+        endpoint.clone(),
+        connection.remote_address(),
+        options.clone(),
+    ));
 
+    // the sessions over the connection share its rate limit
+    let throttle = Throttle::new(options.rate_limit);
+    let own_throttle = throttle.clone();
+    let relayed = async {
+        if options.datagram {
+            Ok((relay_datagrams(&connection, &options).await, None))
+        } else {
+            relay_stream(&connection, own_throttle.clone(), &options).await
+        }
+    };
+    #[cfg(unix)]
+    let relayed = async {
+        match master {
+            Some(master) => {
+                master
+                    .serve_while(&connection, throttle, options.buffer_size, relayed)
+                    .await
+            }
+            None => relayed.await,
+        }
+    };
+    let (reason, reset_code) = relayed.await?;
+    // take the reason before closing, the server may have closed the connection already
+    let close_reason = connection.close_reason();
+    if let (None, Some(reason)) = (&close_reason, reason) {
+        connection.close(close::NORMAL, reason);
+    }
+    // let the close frame reach the server before exiting
+    endpoint.wait_idle().await;
+    info!(
+        remote:% = connection.remote_address();
+        "[client] connection closed: {}",
+        stats::summary(&connection)
+    );
+
+    let exit_code = match close_reason {
+        Some(ConnectionError::ApplicationClosed(close)) => {
+            if close.error_code == close::NORMAL {
+                info!("[client] closed by server: {}", close);
+            } else {
+                error!("[client] closed by server: {}", close);
+            }
+            close::exit_code(close.error_code)
+        }
+        None | Some(ConnectionError::LocallyClosed) => reset_code.map_or(0, close::exit_code),
+        Some(e) => {
+            error!("[client] connection lost: {}", e);
+            1
+        }
+    };
+
+    info!("[client] exit client");
+
+    Ok(ExitCode::from(exit_code))
+}
+
+/// Relays stdin and stdout over a bi-stream, returning the reason to close the
+/// connection with, if any, and the code the server reset the stream with.
+async fn relay_stream(
+    connection: &Connection,
+    throttle: Throttle,
+    options: &Opt,
+) -> Result<(Option<&'static [u8]>, Option<VarInt>), Box<dyn Error>> {
+    let (send, mut recv) = match connection.open_bi().await {
+        Ok(stream) => stream,
+        // the caller reports why the server closed the connection
+        Err(ConnectionError::ApplicationClosed(_)) => return Ok((None, None)),
+        Err(e) => return Err(format!("failed to open stream: {}", e).into()),
+    };
+
+    let received = &Cell::new(0_u64);
+    let sent = &Cell::new(0_u64);
+
+    // returns the error code if the server reset the stream, or the reason to
+    // close the connection with if stdout failed
     let recv_thread = async move {
-        let mut buf = vec![0; 2048];
-        let mut writer = tokio::io::BufWriter::new(tokio::io::stdout());
+        // stdout has no vectored writes, gather the chunks of a read for a
+        // single write
+        let mut writer =
+            tokio::io::BufWriter::with_capacity(options.buffer_size, tokio::io::stdout());
+        let mut batch = Batch::new(options.buffer_size);
 
         loop {
-            match recv.read(&mut buf).await {
-                // Return value of `Ok(0)` signifies that the remote has
-                // closed
+            match batch.read(&mut recv).await {
+                // Return value of `Ok(None)` signifies that the remote has
+                // finished the stream
                 Ok(None) => {
-                    continue;
+                    info!("[client] quic server finished the stream");
+                    return Ok(None);
                 }
-                Ok(Some(n)) => {
+                Ok(Some(mut chunks)) => {
+                    let n = chunks.remaining();
                     debug!("[client] recv data from quic server {} bytes", n);
                     // Copy the data back to socket
-                    match writer.write_all(&buf[..n]).await {
-                        Ok(_) => (),
+                    match writer.write_all_buf(&mut chunks).await {
+                        Ok(_) => received.set(received.get() + n as u64),
+                        // ssh closed the pipe, it's done with the tunnel
+                        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                            info!("[client] stdout closed");
+                            return Err(b"stdout closed".as_slice());
+                        }
                         Err(e) => {
                             error!("[client] write to stdout error: {}", e);
-                            return;
+                            return Err(b"stdout error".as_slice());
                         }
                     }
                 }
+                Err(ReadError::Reset(code)) => {
+                    error!("[client] quic server reset the stream (code {})", code);
+                    return Ok(Some(code));
+                }
+                Err(ReadError::ConnectionLost(ConnectionError::ApplicationClosed(_))) => {
+                    info!("[client] quic server closed the connection");
+                    return Ok(None);
+                }
                 Err(err) => {
                     // Unexpected socket error. There isn't much we can do
                     // here so just stop processing.
                     error!("[client] recv data from quic server error: {}", err);
-                    return;
+                    return Ok(None);
                 }
             }
-            if writer.flush().await.is_err() {
-                error!("[client] recv data flush stdout error");
+            match writer.flush().await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    info!("[client] stdout closed");
+                    return Err(b"stdout closed".as_slice());
+                }
+                Err(e) => {
+                    error!("[client] recv data flush stdout error: {}", e);
+                    return Err(b"stdout error".as_slice());
+                }
             }
         }
     };
 
-    let write_thread = async move {
-        let mut buf = [0; 2048];
-        let mut reader = tokio::io::BufReader::new(tokio::io::stdin());
+    // kept out of the write thread to reset it when stdout fails
+    let mut send = Throttled::new(send, throttle);
+
+    // returns whether stdin reached EOF and the stream was finished cleanly
+    let write_thread = async {
+        // handed to quinn as is once filled, and reused once quinn drops it
+        let mut buf = BytesMut::with_capacity(options.buffer_size);
+        let mut reader = tokio::io::stdin();
 
         loop {
-            match reader.read(&mut buf).await {
-                // Return value of `Ok(0)` signifies that the remote has
+            buf.reserve(options.buffer_size);
+            match reader
+                .read_buf(&mut (&mut buf).limit(options.buffer_size))
+                .await
+            {
+                // Return value of `Ok(0)` signifies that stdin has
                 // closed
-                Ok(n) => {
-                    if n == 0 {
-                        continue;
+                Ok(0) => {
+                    info!("[client] stdin closed, finishing the stream");
+                    if let Err(e) = send.get_mut().finish().await {
+                        info!("[client] finish stream to quic server error: {}", e);
+                        return false;
                     }
+                    return true;
+                }
+                Ok(n) => {
                     debug!("[client] recv data from stdin {} bytes", n);
                     // Copy the data back to socket
-                    if send.write_all(&buf[..n]).await.is_err() {
+                    if send.write_chunk(buf.split().freeze()).await.is_err() {
                         // Unexpected socket error. There isn't much we can
                         // do here so just stop processing.
                         info!("[client] send data to quic server error");
-                        return;
+                        return false;
                     }
+                    sent.set(sent.get() + n as u64);
                 }
                 Err(err) => {
                     // Unexpected socket error. There isn't much we can do
                     // here so just stop processing.
                     info!("[client] recv data from stdin error: {}", err);
-                    return;
+                    return false;
                 }
             }
         }
@@ -198,44 +1412,438 @@ pub async fn run(options: Opt) -> Result<(), Box<dyn Error>> {
 
     let signal_thread = create_signal_thread();
 
+    let mut reset_code = None;
+    let reason: Option<&'static [u8]> = tokio::select! {
+        received = recv_thread => match received {
+            Ok(code) => {
+                reset_code = code;
+                Some(b"stream finished")
+            }
+            // nothing reads the server's data anymore, don't leave its ssh
+            // connection hanging
+            Err(reason) => {
+                discard_unsent(connection, send.get_mut()).await;
+                Some(reason)
+            }
+        },
+        // after a clean EOF keep receiving until the server finishes its side
+        false = write_thread => None,
+        reason = signal_thread => Some(reason),
+    };
+    info!(
+        "[client] stream closed: {} bytes sent, {} bytes received",
+        sent.get(),
+        received.get()
+    );
+    Ok((reason, reset_code))
+}
+
+/// Time allowed for the server to take the reset of a stream before closing.
+const DISCARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Resets the stream, dropping the stdin data not sent yet, and waits for the
+/// server to take the reset. quinn holds the close frame back for congestion
+/// control while anything is queued on a stream, and the close may never go out
+/// if the window is full.
+async fn discard_unsent(connection: &Connection, send: &mut SendStream) {
+    if send.reset(close::NORMAL).is_err() {
+        return;
+    }
+    let deadline = tokio::time::Instant::now() + DISCARD_TIMEOUT;
+    // quinn doesn't wake this when the reset is acknowledged, check every rtt
+    while tokio::time::Instant::now() < deadline {
+        if tokio::time::timeout(connection.rtt(), send.stopped())
+            .await
+            .is_ok()
+        {
+            debug!("[client] stream reset");
+            return;
+        }
+    }
+}
+
+/// Relays stdin and stdout over datagrams, returning the reason to close the
+/// connection with, if any.
+async fn relay_datagrams(connection: &Connection, options: &Opt) -> Option<&'static [u8]> {
+    let signal_thread = create_signal_thread();
+
     tokio::select! {
-        _ = recv_thread => (),
-        _ = write_thread => (),
-        _ = signal_thread => connection.close(0u32.into(), b"signal HUP"),
+        e = datagram::recv_into(connection, tokio::io::stdout()) => {
+            info!("[client] recv datagram from quic server error: {}", e);
+            Some(b"datagram relay finished")
+        }
+        // datagrams can't signal EOF, so keep receiving until the server closes
+        Err(e) = datagram::send_from(connection, tokio::io::stdin(), options.buffer_size) => {
+            error!("[client] send datagram to quic server error: {}", e);
+            Some(b"datagram relay failed")
+        }
+        reason = signal_thread => Some(reason),
     }
+}
 
-    info!("[client] exit client");
+/// Moves the connection to a fresh socket on every SIGUSR1, e.g. after a network
+/// change left the old one with a stale address, relying on QUIC connection migration.
+#[cfg(not(windows))]
+async fn rebind_on_user_signal(endpoint: Endpoint, remote: SocketAddr, options: Opt) {
+    let mut stream = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("[client] create signal stream error: {}", e);
+            return;
+        }
+    };
 
-    Ok(())
+    while stream.recv().await.is_some() {
+        info!("[client] got signal USR1, rebinding");
+        if options.socks5.is_some() {
+            warn!("[client] can't rebind a connection relayed through a SOCKS5 proxy");
+            continue;
+        }
+        if options.dscp.is_some() {
+            warn!("[client] --dscp marking is not kept across a rebind");
+        }
+        if options.no_gso {
+            warn!("[client] --no-gso is not kept across a rebind");
+        }
+        let bind_addr = match options.bind_addr {
+            Some(addr) => SocketAddr::new(addr.ip(), 0),
+            None => unspecified_for(&remote),
+        };
+        let rebound =
+            bind_client_socket(bind_addr, &options).and_then(|socket| Ok(endpoint.rebind(socket)?));
+        match rebound.and_then(|()| Ok(endpoint.local_addr()?)) {
+            Ok(addr) => info!("[client] rebound to: {}", addr),
+            Err(e) => error!("[client] rebind error: {}", e),
+        }
+    }
 }
 
+/// Waits for a signal to close the connection on, returning the reason to close it with.
 #[cfg(windows)]
-fn create_signal_thread() -> impl core::future::Future<Output = ()> {
-    async move {
-        let mut stream = match ctrl_c() {
-            Ok(s) => s,
-            Err(e) => {
-                error!("[client] create signal stream error: {}", e);
-                return;
+async fn create_signal_thread() -> &'static [u8] {
+    let mut stream = match ctrl_c() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("[client] create signal stream error: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    stream.recv().await;
+    info!("[client] got signal Ctrl-C");
+    b"signal Ctrl-C"
+}
+/// Waits for a signal to close the connection on, returning the reason to close it with.
+#[cfg(not(windows))]
+async fn create_signal_thread() -> &'static [u8] {
+    let (mut hup, mut int, mut term) = match (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(hup), Ok(int), Ok(term)) => (hup, int, term),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            error!("[client] create signal stream error: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = hup.recv() => {
+            info!("[client] got signal HUP");
+            b"signal HUP"
+        }
+        _ = int.recv() => {
+            info!("[client] got signal INT");
+            b"signal INT"
+        }
+        _ = term.recv() => {
+            info!("[client] got signal TERM");
+            b"signal TERM"
+        }
+    }
+}
+
+/// Builder of a tunnel to the ssh server behind a quicssh-rs server, for use in
+/// another program. It starts from the defaults of the `client` command.
+pub struct Client {
+    options: Opt,
+}
+
+impl Client {
+    /// Starts building a client of the server at a `quic://host[:port]` URL.
+    pub fn new(url: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Client {
+            options: Opt::try_parse_from(["client", url])?,
+        })
+    }
+
+    /// Verifies the server with the CA certificates in a PEM file, like --ca.
+    pub fn ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.ca_path = Some(path.into());
+        self
+    }
+
+    /// Accepts the server certificate with this SHA-256 fingerprint, like --pin.
+    pub fn pin(mut self, fingerprint: [u8; 32]) -> Self {
+        self.options.pins.push(fingerprint);
+        self
+    }
+
+    /// Authenticates to the server with the certificate chain and private key in
+    /// PEM files, like --client-cert and --client-key.
+    pub fn client_cert(
+        mut self,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Self {
+        self.options.client_cert_path = Some(cert_path.into());
+        self.options.client_key_path = Some(key_path.into());
+        self
+    }
+
+    /// Sends this server name in the handshake instead of the URL host, like --sni.
+    pub fn sni(mut self, sni: impl Into<String>) -> Self {
+        self.options.sni = Some(sni.into());
+        self
+    }
+
+    /// Uses this ALPN protocol identifier, like --alpn.
+    pub fn alpn(mut self, alpn: impl Into<String>) -> Self {
+        self.options.alpn = alpn.into();
+        self
+    }
+
+    /// Connects to the server and opens the tunnel.
+    pub async fn connect(self) -> Result<Tunnel, Box<dyn Error>> {
+        self.options.validate()?;
+        let (endpoint, connection) = connect(&self.options).await?;
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Tunnel {
+            endpoint,
+            connection,
+            send,
+            recv,
+            pool: None,
+        })
+    }
+
+    /// Makes a pool keeping up to `size` connections to the server open once
+    /// their tunnel is closed, for the next tunnels to skip the handshake.
+    pub fn pool(self, size: usize) -> Result<Pool, Box<dyn Error>> {
+        self.options.validate()?;
+        Ok(Pool {
+            options: self.options,
+            shared: Arc::new(PoolShared {
+                idle: Mutex::new(Vec::new()),
+                size,
+                ttl: Mutex::new(DEFAULT_POOL_TTL),
+                reaping: AtomicBool::new(false),
+            }),
+        })
+    }
+}
+
+/// Time an unused connection is kept in a pool by default.
+const DEFAULT_POOL_TTL: Duration = Duration::from_secs(30);
+
+/// Connections to the server of a client, reused by its tunnels: a tunnel opens
+/// a stream on a connection left open by a closed one, and only connects when
+/// none is. Each connection carries one tunnel at a time, so the server needn't
+/// allow more than one stream per connection.
+///
+/// A pool belongs to one client, so its connections all go to the same server
+/// with the same SNI and certificate checks. Unused connections are closed
+/// after a while, see [`Pool::idle_ttl`].
+pub struct Pool {
+    options: Opt,
+    shared: Arc<PoolShared>,
+}
+
+struct PoolShared {
+    /// Unused connections, the most recently used last.
+    idle: Mutex<Vec<IdleConnection>>,
+    size: usize,
+    ttl: Mutex<Duration>,
+    /// Whether the task closing expired connections runs.
+    reaping: AtomicBool,
+}
+
+struct IdleConnection {
+    endpoint: Endpoint,
+    connection: Connection,
+    since: Instant,
+}
+
+impl Pool {
+    /// Closes the connections unused for this long, 30 seconds by default. They
+    /// are checked at most every 100ms, so a zero TTL keeps them that long.
+    pub fn idle_ttl(self, ttl: Duration) -> Self {
+        *self.shared.ttl.lock().unwrap() = ttl;
+        self
+    }
+
+    /// Returns the number of unused connections in the pool.
+    pub fn idle(&self) -> usize {
+        self.shared.idle.lock().unwrap().len()
+    }
+
+    /// Opens a tunnel over an unused connection of the pool, or over a new one
+    /// if there is none. Closing the tunnel puts the connection back, dropping it
+    /// closes the connection, as its stream may have been left mid-transfer.
+    pub async fn connect(&self) -> Result<Tunnel, Box<dyn Error>> {
+        self.start_reaping();
+        while let Some(idle) = self.take_idle() {
+            match idle.connection.open_bi().await {
+                Ok((send, recv)) => {
+                    debug!("[client] reusing pooled connection");
+                    return Ok(Tunnel {
+                        endpoint: idle.endpoint,
+                        connection: idle.connection,
+                        send,
+                        recv,
+                        pool: Some(self.shared.clone()),
+                    });
+                }
+                Err(e) => debug!("[client] pooled connection unusable: {}", e),
             }
-        };
+        }
+        let (endpoint, connection) = connect(&self.options).await?;
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Tunnel {
+            endpoint,
+            connection,
+            send,
+            recv,
+            pool: Some(self.shared.clone()),
+        })
+    }
 
-        stream.recv().await;
-        info!("[client] got signal Ctrl-C");
+    /// Takes the most recently used connection still open.
+    fn take_idle(&self) -> Option<IdleConnection> {
+        let mut idle = self.shared.idle.lock().unwrap();
+        while let Some(connection) = idle.pop() {
+            if connection.connection.close_reason().is_none() {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    fn start_reaping(&self) {
+        if !self.shared.reaping.swap(true, Ordering::Relaxed) {
+            tokio::spawn(reap(Arc::downgrade(&self.shared)));
+        }
     }
 }
-#[cfg(not(windows))]
-fn create_signal_thread() -> impl core::future::Future<Output = ()> {
-    async move {
-        let mut stream = match signal(SignalKind::hangup()) {
-            Ok(s) => s,
-            Err(e) => {
-                error!("[client] create signal stream error: {}", e);
-                return;
+
+impl PoolShared {
+    /// Keeps the connection for another tunnel, unless the pool is full.
+    fn put_back(&self, endpoint: Endpoint, connection: Connection) {
+        if connection.close_reason().is_some() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() >= self.size {
+            connection.close(close::NORMAL, b"pool full");
+            return;
+        }
+        idle.push(IdleConnection {
+            endpoint,
+            connection,
+            since: Instant::now(),
+        });
+    }
+
+    /// Closes the connections unused for the TTL, returning the time until the
+    /// next one expires.
+    fn close_expired(&self) -> Duration {
+        let ttl = *self.ttl.lock().unwrap();
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|idle| {
+            let expired = idle.since.elapsed() >= ttl;
+            if expired {
+                debug!("[client] closing pooled connection unused for {:?}", ttl);
+                idle.connection.close(close::NORMAL, b"pool idle");
             }
+            !expired
+        });
+        idle.first()
+            .map_or(ttl, |oldest| ttl.saturating_sub(oldest.since.elapsed()))
+    }
+}
+
+/// Shortest wait between two checks for expired connections.
+const MIN_REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Closes the expired connections of the pool until it's dropped.
+async fn reap(shared: Weak<PoolShared>) {
+    loop {
+        let next = match shared.upgrade() {
+            Some(shared) => shared.close_expired(),
+            None => return,
         };
+        // a zero TTL would have it spin
+        tokio::time::sleep(next.max(MIN_REAP_INTERVAL)).await;
+    }
+}
+
+/// A tunnel to the ssh server: what is written to it is relayed to the ssh
+/// server, and what the ssh server sends back can be read from it.
+///
+/// Shutting down the writing side tells the ssh server no more data is coming,
+/// like stdin EOF does for the `client` command.
+pub struct Tunnel {
+    endpoint: Endpoint,
+    connection: Connection,
+    send: SendStream,
+    recv: RecvStream,
+    pool: Option<Arc<PoolShared>>,
+}
+
+impl Tunnel {
+    /// Closes the connection, waiting until the server is told. Dropping the
+    /// tunnel instead closes it without waiting.
+    ///
+    /// A tunnel of a [`Pool`] ends its stream instead and leaves the connection
+    /// to the pool.
+    pub async fn close(mut self) {
+        if let Some(pool) = self.pool {
+            // the server frees the stream for the next tunnel once both sides end
+            let _ = self.recv.stop(close::NORMAL);
+            let _ = self.send.finish().await;
+            pool.put_back(self.endpoint, self.connection);
+            return;
+        }
+        self.connection.close(close::NORMAL, b"tunnel closed");
+        self.endpoint.wait_idle().await;
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
 
-        stream.recv().await;
-        info!("[client] got signal HUP");
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
     }
 }
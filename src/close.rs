@@ -0,0 +1,50 @@
+//! Application close codes of the QUIC connection, shared by the client and the server.
+//! They are also used as the error codes to reset a single stream with.
+//!
+//! The tunnel only relays the encrypted ssh stream, so the exit status of the remote
+//! command itself can't be seen here; these codes tell how the tunnel ended instead.
+
+use quinn::VarInt;
+
+/// The tunnel ended normally, e.g. the ssh server closed the connection.
+pub const NORMAL: VarInt = VarInt::from_u32(0);
+/// The server is shutting down.
+pub const SERVER_SHUTDOWN: VarInt = VarInt::from_u32(1);
+/// The connection to the ssh server failed after it was set up.
+pub const BACKEND_ERROR: VarInt = VarInt::from_u32(2);
+/// The server has no room for another connection.
+pub const SERVER_BUSY: VarInt = VarInt::from_u32(3);
+/// The client opened connections faster than the server allows.
+pub const RATE_LIMITED: VarInt = VarInt::from_u32(4);
+/// The client address is not allowed to connect.
+pub const ACCESS_DENIED: VarInt = VarInt::from_u32(5);
+/// The server could not resolve or connect to the ssh server.
+pub const BACKEND_UNREACHABLE: VarInt = VarInt::from_u32(6);
+/// The server timed out connecting to the ssh server.
+pub const BACKEND_TIMEOUT: VarInt = VarInt::from_u32(7);
+/// No data went through the stream for the server's stream timeout.
+pub const STREAM_TIMEOUT: VarInt = VarInt::from_u32(8);
+/// The connection reached the server's maximum duration.
+pub const MAX_DURATION: VarInt = VarInt::from_u32(9);
+/// The client negotiated the route header but didn't send a valid one in time.
+pub const NO_ROUTE: VarInt = VarInt::from_u32(10);
+
+/// Maps a close code sent by the server to the exit code of the client process.
+///
+/// Unknown codes are mapped to 1, same as the errors of the client itself.
+pub fn exit_code(code: VarInt) -> u8 {
+    match code {
+        NORMAL => 0,
+        SERVER_SHUTDOWN => 65,
+        BACKEND_ERROR => 66,
+        SERVER_BUSY => 67,
+        RATE_LIMITED => 68,
+        ACCESS_DENIED => 69,
+        BACKEND_UNREACHABLE => 70,
+        BACKEND_TIMEOUT => 71,
+        STREAM_TIMEOUT => 72,
+        MAX_DURATION => 73,
+        NO_ROUTE => 74,
+        _ => 1,
+    }
+}
@@ -0,0 +1,33 @@
+use clap::ValueEnum;
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::TransportConfig;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Congestion controller algorithms provided by quinn.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Controller {
+    Cubic,
+    #[value(name = "newreno")]
+    NewReno,
+    // experimental in quinn, but can do much better on high-BDP links
+    Bbr,
+}
+
+impl Controller {
+    /// Makes the connections of `transport_config` use this controller.
+    pub fn apply(self, transport_config: &mut TransportConfig) {
+        match self {
+            Controller::Cubic => {
+                transport_config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Controller::NewReno => {
+                transport_config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Controller::Bbr => {
+                transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+    }
+}
@@ -0,0 +1,156 @@
+//! Sharing one QUIC connection between ssh sessions through a unix socket, like
+//! ssh's ControlMaster, for `--control-socket`.
+//!
+//! The first client on the socket connects to the server and becomes the master.
+//! The clients started after it attach to the socket, and the master relays each
+//! of them over a bi-stream of its connection. Once its own session ends the
+//! master removes the socket, so that the next client becomes a master again,
+//! and exits after the attached sessions end.
+
+use log::{debug, info, warn};
+use quinn::Connection;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
+
+use crate::relay;
+use crate::throttle::{Throttle, Throttled};
+
+pub enum Control {
+    /// Another client is the master, relay through it.
+    Attach(UnixStream),
+    /// No master was running, this client is now.
+    Master(Master),
+}
+
+/// Attaches to the master on the socket, or binds the socket to become the
+/// master if none answers.
+pub async fn open(path: &Path) -> io::Result<Control> {
+    // a second round for another client becoming the master in between
+    for _ in 0..2 {
+        match UnixStream::connect(path).await {
+            Ok(stream) => return Ok(Control::Attach(stream)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            // left behind by a master that didn't exit cleanly
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("[client] removing stale control socket: {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e),
+        }
+        match UnixListener::bind(path) {
+            Ok(listener) => {
+                return Ok(Control::Master(Master {
+                    listener,
+                    path: path.to_owned(),
+                }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "another client keeps taking over the control socket",
+    ))
+}
+
+/// Relays stdin and stdout through the master, until the master closes the
+/// session.
+pub async fn relay_attached(stream: UnixStream) -> io::Result<()> {
+    let (mut from_master, mut to_master) = stream.into_split();
+    let sending = async {
+        tokio::io::copy(&mut tokio::io::stdin(), &mut to_master).await?;
+        to_master.shutdown().await
+    };
+    let receiving = async {
+        let mut stdout = tokio::io::stdout();
+        tokio::io::copy(&mut from_master, &mut stdout).await?;
+        stdout.flush().await
+    };
+    tokio::pin!(receiving);
+    tokio::select! {
+        received = &mut receiving => return received,
+        sent = sending => sent?,
+    }
+    // after stdin EOF keep receiving until the master closes the session
+    receiving.await
+}
+
+/// The listening control socket, removed when dropped.
+pub struct Master {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Master {
+    /// Relays the clients attaching to the socket over the connection while
+    /// `session`, the master's own, runs. Then stops listening and waits for the
+    /// attached sessions to end before returning what `session` returned.
+    pub async fn serve_while<T>(
+        self,
+        connection: &Connection,
+        throttle: Throttle,
+        buffer_size: usize,
+        session: impl Future<Output = T>,
+    ) -> T {
+        info!(
+            "[client] control socket listening on: {}",
+            self.path.display()
+        );
+        let mut attached = JoinSet::new();
+        tokio::pin!(session);
+        let result = loop {
+            tokio::select! {
+                result = &mut session => break result,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let relayed = relay(stream, connection.clone(), throttle.clone(), buffer_size);
+                        attached.spawn(relayed);
+                    }
+                    Err(e) => warn!("[client] accept on control socket error: {}", e),
+                },
+                Some(_) = attached.join_next() => (),
+            }
+        };
+
+        drop(self);
+        if !attached.is_empty() {
+            info!(
+                "[client] waiting for {} attached sessions to end",
+                attached.len()
+            );
+        }
+        while attached.join_next().await.is_some() {}
+        result
+    }
+}
+
+/// Relays an attached client over a new bi-stream.
+async fn relay(stream: UnixStream, connection: Connection, throttle: Throttle, buffer_size: usize) {
+    let (send, mut recv) = match connection.open_bi().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("[client] open stream for attached session error: {}", e);
+            return;
+        }
+    };
+    info!("[client] attached session opened: {}", send.id());
+    let mut send = Throttled::new(send, throttle);
+    match relay::copy_bidirectional(stream, &mut recv, &mut send, buffer_size).await {
+        Ok((sent, received)) => info!(
+            "[client] attached session closed: {} bytes sent, {} bytes received",
+            sent, received
+        ),
+        Err(e) => info!("[client] attached session error: {}", e),
+    }
+}
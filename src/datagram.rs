@@ -0,0 +1,74 @@
+//! Experimental forwarding over unreliable QUIC datagrams, enabled with `--datagram`.
+//!
+//! Datagrams avoid head-of-line blocking, but they may be lost or reordered and ssh
+//! tolerates neither: a single lost datagram breaks the session. Client and server
+//! agree on the mode with the ALPN token returned by [`alpn`].
+
+use bytes::{BufMut, BytesMut};
+use quinn::{crypto, Connection};
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Returns the ALPN token selecting the datagram mode, derived from the one of
+/// the stream mode.
+pub fn alpn(base: &str) -> Vec<u8> {
+    format!("{}-dgram", base).into_bytes()
+}
+
+/// Size in bytes of the buffer for received datagrams not yet read.
+pub const RECEIVE_BUFFER_SIZE: usize = 1_250_000;
+
+/// Returns whether the datagram mode was negotiated on the connection.
+pub fn negotiated(connection: &Connection, base: &str) -> bool {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .is_some_and(|protocol| protocol == alpn(base))
+}
+
+/// Sends everything read from `reader` as datagrams until EOF.
+pub async fn send_from<R>(
+    connection: &Connection,
+    mut reader: R,
+    buffer_size: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(buffer_size);
+    loop {
+        // a datagram has to fit in a single packet
+        let max = connection
+            .max_datagram_size()
+            .ok_or("peer doesn't accept datagrams")?;
+        let max = max.min(buffer_size);
+        buf.reserve(max);
+        let n = reader.read_buf(&mut (&mut buf).limit(max)).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        // handed to quinn as is, the space is reused once it's sent
+        connection.send_datagram(buf.split().freeze())?;
+    }
+}
+
+/// Writes every received datagram to `writer`, until the connection is lost or
+/// writing fails.
+pub async fn recv_into<W>(connection: &Connection, mut writer: W) -> Box<dyn Error + Send + Sync>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let data = match connection.read_datagram().await {
+            Ok(data) => data,
+            Err(e) => return e.into(),
+        };
+        if let Err(e) = writer.write_all(&data).await {
+            return e.into();
+        }
+        if let Err(e) = writer.flush().await {
+            return e.into();
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use log4rs::encode::{Encode, Write};
+use serde::ser::{SerializeMap, Serializer};
+
+/// Encodes every record as a JSON object on its own line, with the fields `ts`,
+/// `level`, `target` and `msg`, followed by the key-values of the record such as
+/// `remote` and `sni`.
+#[derive(Debug, Default)]
+pub struct JsonEncoder;
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        let mut fields = Fields(Vec::new());
+        record.key_values().visit(&mut fields)?;
+
+        let mut serializer = serde_json::Serializer::new(&mut *w);
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry(
+            "ts",
+            &Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        )?;
+        map.serialize_entry("level", record.level().as_str())?;
+        map.serialize_entry("target", record.target())?;
+        map.serialize_entry("msg", &record.args().to_string())?;
+        for (key, value) in &fields.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+//! Writing the TLS secrets of the connections to a file, for `--keylog`, so that
+//! Wireshark can decrypt a capture.
+//!
+//! The lines follow the NSS key log format read by Wireshark, the same as with
+//! `SSLKEYLOGFILE` in browsers. Anyone who can read the file and a capture can
+//! decrypt the whole session, hence the explicit flag rather than the variable.
+
+use log::error;
+use rustls::KeyLog;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+struct KeyLogFile {
+    file: Mutex<File>,
+    side: &'static str,
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("[{}] write key log error: {}", self.side, e);
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// Opens the file for appending, creating it readable by the owner only, and
+/// warns that the secrets are being written there.
+pub fn open(path: &Path, side: &'static str) -> io::Result<Arc<dyn KeyLog>> {
+    let mut file = std::fs::OpenOptions::new();
+    file.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+    let file = file.open(path)?;
+    error!(
+        "[{}] TLS KEY LOGGING IS ON: the secrets of every connection are written to {}, which decrypts them. Only use --keylog for debugging",
+        side,
+        path.display()
+    );
+    Ok(Arc::new(KeyLogFile {
+        file: Mutex::new(file),
+        side,
+    }))
+}
@@ -0,0 +1,36 @@
+//! A QUIC proxy for ssh. The client side relays a local ssh client over QUIC to
+//! the server side, which relays it on to an ssh server over TCP.
+//!
+//! [`Client`] and [`Server`] embed either side in another program; the
+//! `quicssh-rs` binary is a command line wrapper around the same code.
+
+mod acl;
+mod audit;
+pub mod client;
+pub mod close;
+mod congestion;
+#[cfg(unix)]
+mod control;
+mod datagram;
+mod idle;
+pub mod json_log;
+mod keylog;
+mod metrics;
+mod pem;
+mod proxy_protocol;
+mod ratelimit;
+mod relay;
+mod reset_key;
+mod resolver;
+pub mod selftest;
+pub mod server;
+mod sni;
+mod socket;
+mod socks5;
+mod stats;
+mod throttle;
+mod traffic;
+mod x509;
+
+pub use client::{Client, Pool, Tunnel};
+pub use server::{Router, Server};
@@ -1,27 +1,82 @@
-mod client;
-mod server;
-
 use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
 
-use clap::{Parser, Subcommand};
+use clap::{
+    error::ErrorKind, ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
 use log::{error, LevelFilter};
-use std::{path::PathBuf, str};
+use quicssh_rs::{client, json_log, selftest, server};
+use std::{path::PathBuf, process::ExitCode, str};
+
+/// `--version` output, with the libraries which matter for interoperability.
+/// rustls 0.21 only supports the ring crypto provider.
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\nquinn ",
+    env!("QUINN_VERSION"),
+    "\nrustls ",
+    env!("RUSTLS_VERSION"),
+    " (ring crypto provider)"
+);
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, long_version = LONG_VERSION, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
-    /// Location of log, Default if
-    #[clap(value_parser, long = "log")]
+    /// File to append the log to instead of stderr, e.g. to keep it out of the
+    /// terminal of ssh when run as its ProxyCommand
+    #[clap(
+        value_parser,
+        long = "log-file",
+        alias = "log",
+        global = true,
+        help_heading = "Logging"
+    )]
     log_file: Option<PathBuf>,
     /// Log level, Default Error
-    #[clap(long)]
+    #[clap(long, global = true, help_heading = "Logging")]
     log_level: Option<LevelFilter>,
+    /// Log more, -v for debug and -vv for trace level
+    #[clap(short, long, action = ArgAction::Count, global = true, help_heading = "Logging", conflicts_with_all = ["log_level", "quiet"])]
+    verbose: u8,
+    /// Log nothing, not even errors
+    #[clap(
+        short,
+        long,
+        global = true,
+        help_heading = "Logging",
+        conflicts_with = "log_level"
+    )]
+    quiet: bool,
+    /// Log format, json writes one object per line
+    #[clap(
+        long,
+        value_enum,
+        default_value = "text",
+        global = true,
+        help_heading = "Logging"
+    )]
+    log_format: LogFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    fn encoder(self) -> Box<dyn Encode> {
+        match self {
+            LogFormat::Text => Box::<PatternEncoder>::default(),
+            LogFormat::Json => Box::<json_log::JsonEncoder>::default(),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -29,22 +84,50 @@ enum Commands {
     /// Server
     Server(server::Opt),
     /// Client
+    ///
+    /// Session tickets are only kept in memory, so each run makes a full
+    /// handshake without 0-RTT.
     Client(client::Opt),
+    /// Check that QUIC works over loopback, with an in-process server, client
+    /// and echo backend
+    Selftest,
 }
 
-fn main() {
-    let args = Cli::parse();
+fn main() -> ExitCode {
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Commands::Client(client) = &mut args.command {
+        let client_matches = matches.subcommand_matches("client").unwrap();
+        if let Err(e) = client.load_conf(client_matches) {
+            Cli::command().error(ErrorKind::Io, e).exit();
+        }
+        if let Err(e) = client.load_env(client_matches) {
+            Cli::command().error(ErrorKind::InvalidValue, e).exit();
+        }
+    }
+    if let Err(e) = match &args.command {
+        Commands::Server(_) | Commands::Selftest => Ok(()),
+        Commands::Client(client) => client.validate(),
+    } {
+        Cli::command().error(ErrorKind::ArgumentConflict, e).exit();
+    }
 
-    let level = match args.log_level {
-        Some(log_level) => log_level,
-        None => LevelFilter::Error,
+    let level = match (args.log_level, args.verbose, args.quiet) {
+        (Some(log_level), _, _) => log_level,
+        (None, _, true) => LevelFilter::Off,
+        (None, 0, false) => LevelFilter::Error,
+        (None, 1, false) => LevelFilter::Debug,
+        (None, _, false) => LevelFilter::Trace,
     };
     let config = match args.log_file {
         Some(log_file) => {
             let logfile = FileAppender::builder()
-                .encoder(Box::<PatternEncoder>::default())
-                .build(log_file)
-                .unwrap();
+                .encoder(args.log_format.encoder())
+                .build(&log_file)
+                .unwrap_or_else(|e| {
+                    let e = format!("opening log file {} failed: {}", log_file.display(), e);
+                    Cli::command().error(ErrorKind::Io, e).exit()
+                });
 
             Config::builder()
                 .appender(Appender::builder().build("logfile", Box::new(logfile)))
@@ -53,7 +136,7 @@ fn main() {
         }
         None => {
             let stderr = ConsoleAppender::builder()
-                .encoder(Box::<PatternEncoder>::default())
+                .encoder(args.log_format.encoder())
                 .target(Target::Stderr)
                 .build();
             Config::builder()
@@ -66,23 +149,27 @@ fn main() {
     log4rs::init_config(config).unwrap();
 
     match args.command {
-        Commands::Server(server) => {
+        Commands::Server(mut server) => {
+            server.set_json_output(matches!(args.log_format, LogFormat::Json));
             let err = server::run(server);
             match err {
-                Ok(_) => {}
+                Ok(_) => ExitCode::SUCCESS,
                 Err(e) => {
                     error!("Error: {:#?}", e);
+                    ExitCode::FAILURE
                 }
             }
         }
         Commands::Client(client) => {
             let err = client::run(client);
             match err {
-                Ok(_) => {}
+                Ok(code) => code,
                 Err(e) => {
                     error!("Error: {:#?}", e);
+                    ExitCode::FAILURE
                 }
             }
         }
+        Commands::Selftest => selftest::run(),
     }
 }
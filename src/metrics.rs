@@ -0,0 +1,108 @@
+//! Server counters, served in the Prometheus text format with `--metrics`.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+pub struct Metrics {
+    pub active_connections: AtomicU64,
+    pub connections: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    pub backend_connect_failures: AtomicU64,
+    pub bytes_to_backend: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+}
+
+/// The counters of the process, which every server in it adds to, those of the
+/// library included.
+pub static METRICS: Metrics = Metrics {
+    active_connections: AtomicU64::new(0),
+    connections: AtomicU64::new(0),
+    handshake_failures: AtomicU64::new(0),
+    backend_connect_failures: AtomicU64::new(0),
+    bytes_to_backend: AtomicU64::new(0),
+    bytes_to_client: AtomicU64::new(0),
+};
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut body = String::new();
+        for (name, kind, help, counter) in [
+            (
+                "quicssh_active_connections",
+                "gauge",
+                "QUIC connections currently open",
+                &self.active_connections,
+            ),
+            (
+                "quicssh_connections_total",
+                "counter",
+                "QUIC connections accepted",
+                &self.connections,
+            ),
+            (
+                "quicssh_handshake_failures_total",
+                "counter",
+                "QUIC handshakes that failed",
+                &self.handshake_failures,
+            ),
+            (
+                "quicssh_backend_connect_failures_total",
+                "counter",
+                "Failed connection attempts to the ssh server",
+                &self.backend_connect_failures,
+            ),
+            (
+                "quicssh_bytes_to_backend_total",
+                "counter",
+                "Bytes relayed from clients to the ssh server",
+                &self.bytes_to_backend,
+            ),
+            (
+                "quicssh_bytes_to_client_total",
+                "counter",
+                "Bytes relayed from the ssh server to clients",
+                &self.bytes_to_client,
+            ),
+        ] {
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} {}", name, kind);
+            let _ = writeln!(body, "{} {}", name, counter.load(Relaxed));
+        }
+        body
+    }
+}
+
+/// Answers every HTTP request on the listener with the metrics, whatever its path.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(respond(stream));
+            }
+            Err(e) => error!("[server] accept metrics connection error: {}", e),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream) {
+    // the request itself doesn't matter, only wait for it to arrive
+    let mut buf = [0; 1024];
+    if let Err(e) = stream.read(&mut buf).await {
+        debug!("[server] read metrics request error: {}", e);
+        return;
+    }
+    let body = METRICS.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("[server] write metrics response error: {}", e);
+    }
+}
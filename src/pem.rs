@@ -0,0 +1,25 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Loads the certificates in a PEM file, either a single one or a bundle.
+pub fn load_certs(path: &Path) -> Result<Vec<rustls::Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .map(|cert| cert.map(|der| rustls::Certificate(der.to_vec())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+/// Loads the first private key in a PEM file, either PKCS#8, PKCS#1 (RSA) or SEC1.
+pub fn load_private_key(path: &Path) -> Result<rustls::PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(rustls::PrivateKey(key.secret_der().to_vec())),
+        None => Err(format!("no private key found in {}", path.display()).into()),
+    }
+}
@@ -0,0 +1,45 @@
+//! PROXY protocol v2 headers, which tell the ssh server the real address of the
+//! client instead of the address of quicssh-rs. See
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command.
+const VERSION_COMMAND: u8 = 0x21;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Builds the header for a connection from `source` to `destination`.
+///
+/// IPv4-mapped addresses are sent as IPv4 when both ends allow it, otherwise
+/// an IPv4 end is mapped to IPv6 so that both have the same family.
+pub fn header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_COMMAND);
+    // the length counts the addresses and the ports
+    match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(TCP_OVER_IPV4);
+            header.extend_from_slice(&12_u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.push(TCP_OVER_IPV6);
+            header.extend_from_slice(&36_u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(src).octets());
+            header.extend_from_slice(&to_ipv6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Maximum number of addresses tracked, the least recently seen is evicted beyond it.
+const MAX_TRACKED: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    last_seen: Instant,
+    /// The bucket's key in `RateLimiter::by_use`.
+    used: u64,
+}
+
+/// Token buckets limiting the rate of new connections per client address.
+///
+/// The addresses are those of the clients' first packets, which are only
+/// validated with a Retry. Without one, spoofed sources can evict the bucket of
+/// a real client, which then comes back full.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    /// The tracked addresses, the least recently seen first.
+    by_use: BTreeMap<u64, IpAddr>,
+    uses: u64,
+}
+
+impl RateLimiter {
+    /// Allows `rate` connections per second per address, and bursts of up to `burst`.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: f64::from(burst),
+            buckets: HashMap::new(),
+            by_use: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Takes a token for a new connection from the address, returning whether it had one.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_TRACKED && !self.buckets.contains_key(&ip) {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.buckets.remove(&oldest);
+            }
+        }
+
+        self.uses += 1;
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            last_seen: now,
+            used: 0,
+        });
+        self.by_use.remove(&bucket.used);
+        bucket.used = self.uses;
+        self.by_use.insert(self.uses, ip);
+
+        let refill = now.duration_since(bucket.last_seen).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.last_seen = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn ip(n: u32) -> IpAddr {
+        Ipv4Addr::from(n).into()
+    }
+
+    #[test]
+    fn allows_a_burst_then_refills_at_the_rate() {
+        let mut limiter = RateLimiter::new(2.0, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip(1), start));
+        }
+        assert!(!limiter.allow_at(ip(1), start));
+        // another address has a bucket of its own
+        assert!(limiter.allow_at(ip(2), start));
+
+        // half a second at 2/s is one token
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow_at(ip(1), later));
+        assert!(!limiter.allow_at(ip(1), later));
+
+        // refilling stops at the burst
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip(1), much_later));
+        }
+        assert!(!limiter.allow_at(ip(1), much_later));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_seen_address() {
+        let mut limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+        for n in 0..MAX_TRACKED as u32 {
+            assert!(limiter.allow_at(ip(n), now));
+        }
+        // seen again, so the next to go is address 1
+        assert!(!limiter.allow_at(ip(0), now));
+
+        assert!(limiter.allow_at(ip(u32::MAX), now));
+        assert_eq!(limiter.buckets.len(), MAX_TRACKED);
+        assert_eq!(limiter.by_use.len(), MAX_TRACKED);
+        assert!(limiter.buckets.contains_key(&ip(0)));
+        assert!(!limiter.buckets.contains_key(&ip(1)));
+        // address 0 kept its empty bucket
+        assert!(!limiter.allow_at(ip(0), now));
+    }
+}
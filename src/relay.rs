@@ -0,0 +1,133 @@
+//! Relaying between a QUIC bi-stream and a byte stream without a copy through
+//! an intermediate buffer on the QUIC side.
+//!
+//! Received data is taken from quinn as the `Bytes` chunks it reassembled, in
+//! batches written with a single vectored write, as a chunk is only the data of
+//! a packet. Data to send is read into a `BytesMut` whose filled part is handed
+//! to quinn as is. The space of the buffer is reclaimed once quinn drops the
+//! chunks, when the peer acknowledged them.
+
+use crate::throttle::Throttled;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use quinn::{ReadError, RecvStream, SendStream};
+use std::io::{self, IoSlice};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Smallest payload of a QUIC packet, so a chunk is at least about as large
+/// unless the sender had less to send.
+const MIN_PACKET: usize = 1200;
+
+/// Room for the chunks of a read from a stream, reused across reads.
+pub struct Batch(Vec<Bytes>);
+
+impl Batch {
+    /// Makes room for as many chunks as full packets fit in `buffer_size`.
+    pub fn new(buffer_size: usize) -> Self {
+        Batch(vec![Bytes::new(); buffer_size.div_ceil(MIN_PACKET)])
+    }
+
+    /// Takes the chunks received so far, waiting for one if there are none.
+    /// Returns `None` once the stream is finished.
+    pub async fn read(&mut self, recv: &mut RecvStream) -> Result<Option<Chunks<'_>>, ReadError> {
+        let read = recv.read_chunks(&mut self.0).await?;
+        Ok(read.map(|n| Chunks(&mut self.0[..n])))
+    }
+}
+
+/// The chunks of a read, as one buffer to write.
+pub struct Chunks<'a>(&'a mut [Bytes]);
+
+impl Buf for Chunks<'_> {
+    fn remaining(&self) -> usize {
+        self.0.iter().map(Bytes::len).sum()
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.0
+            .iter()
+            .find(|chunk| !chunk.is_empty())
+            .map_or(&[], |chunk| chunk)
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        for chunk in self.0.iter_mut() {
+            let n = cnt.min(chunk.len());
+            chunk.advance(n);
+            cnt -= n;
+        }
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let chunks = self.0.iter().filter(|chunk| !chunk.is_empty());
+        let mut n = 0;
+        for (slice, chunk) in dst.iter_mut().zip(chunks) {
+            *slice = IoSlice::new(chunk);
+            n += 1;
+        }
+        n
+    }
+}
+
+/// Relays in both directions until each side reached EOF, propagating it to the
+/// other as a half-close. Returns the bytes sent into and received from the
+/// QUIC stream.
+pub async fn copy_bidirectional<S>(
+    stream: S,
+    recv: &mut RecvStream,
+    send: &mut Throttled<SendStream>,
+    buffer_size: usize,
+) -> io::Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tokio::try_join!(
+        send_from(&mut reader, send, buffer_size),
+        recv_into(recv, &mut writer, buffer_size),
+    )
+}
+
+/// Sends what `reader` gives, in reads of up to `buffer_size` bytes, until EOF,
+/// then finishes the stream.
+pub async fn send_from<R>(
+    reader: &mut R,
+    send: &mut Throttled<SendStream>,
+    buffer_size: usize,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(buffer_size);
+    let mut sent = 0;
+    loop {
+        buf.reserve(buffer_size);
+        let n = reader.read_buf(&mut (&mut buf).limit(buffer_size)).await?;
+        if n == 0 {
+            send.get_mut().finish().await?;
+            return Ok(sent);
+        }
+        send.write_chunk(buf.split().freeze()).await?;
+        sent += n as u64;
+    }
+}
+
+/// Writes the chunks received on the stream, in batches of about `buffer_size`
+/// bytes at most, to `writer` until the stream is finished, then shuts `writer`
+/// down.
+pub async fn recv_into<W>(
+    recv: &mut RecvStream,
+    writer: &mut W,
+    buffer_size: usize,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut batch = Batch::new(buffer_size);
+    let mut received = 0;
+    while let Some(mut chunks) = batch.read(recv).await? {
+        received += chunks.remaining() as u64;
+        writer.write_all_buf(&mut chunks).await?;
+    }
+    writer.shutdown().await?;
+    Ok(received)
+}
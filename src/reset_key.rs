@@ -0,0 +1,76 @@
+//! The key stateless reset tokens are derived from, for `--reset-key`.
+//!
+//! quinn picks a random key on every start, so a restarted server can't prove to
+//! the clients of the previous instance that their connections are gone, and they
+//! wait out their idle timeout. Kept in a file, the key survives restarts.
+
+use log::info;
+use quinn::crypto::{CryptoError, HmacKey};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Length of a generated key, the block size of SHA-256.
+const KEY_LEN: usize = 64;
+/// Shortest key accepted from the file.
+const MIN_KEY_LEN: usize = 32;
+
+struct ResetKey(hmac::Key);
+
+impl HmacKey for ResetKey {
+    fn sign(&self, data: &[u8], signature_out: &mut [u8]) {
+        let tag = hmac::sign(&self.0, data);
+        signature_out.copy_from_slice(tag.as_ref());
+    }
+
+    fn signature_len(&self) -> usize {
+        hmac::HMAC_SHA256.digest_algorithm().output_len()
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> Result<(), CryptoError> {
+        hmac::verify(&self.0, data, signature).map_err(|_| CryptoError)
+    }
+}
+
+/// Loads the key from the file, generating and saving it there if it doesn't
+/// exist yet.
+pub fn load_or_generate(path: &Path) -> Result<Arc<dyn HmacKey>, Box<dyn Error>> {
+    let key = if path.exists() {
+        info!(
+            "[server] loading stateless reset key from: {}",
+            path.display()
+        );
+        let key = std::fs::read(path)?;
+        if key.len() < MIN_KEY_LEN {
+            return Err(format!(
+                "reset key {} holds {} bytes, at least {} are needed",
+                path.display(),
+                key.len(),
+                MIN_KEY_LEN
+            )
+            .into());
+        }
+        key
+    } else {
+        let mut key = vec![0; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| "generating the reset key failed")?;
+        let mut file = std::fs::OpenOptions::new();
+        file.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+        file.open(path)
+            .and_then(|mut file| file.write_all(&key))
+            .map_err(|e| format!("saving reset key to {} failed: {}", path.display(), e))?;
+        info!(
+            "[server] saved generated stateless reset key to: {}",
+            path.display()
+        );
+        key
+    };
+    Ok(Arc::new(ResetKey(hmac::Key::new(hmac::HMAC_SHA256, &key))))
+}
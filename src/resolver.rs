@@ -0,0 +1,128 @@
+//! The resolver command of the conf file, which picks the ssh server of the SNIs
+//! without an entry of their own, e.g. a container started per user.
+//!
+//! The command reads the SNI on a line of stdin and prints the ssh server as
+//! `host:port` or `unix:path`. Printing nothing, failing or timing out leaves the
+//! connection to the `default` entry.
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::{timeout, Instant};
+
+/// Maximum number of answers kept, the cache is emptied beyond it.
+const MAX_CACHED: usize = 4096;
+
+fn default_timeout() -> u64 {
+    2000
+}
+
+fn default_cache() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Resolver {
+    /// The program to run and its arguments.
+    command: Vec<String>,
+    /// Milliseconds to wait for the answer.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Seconds to reuse an answer for the same SNI, 0 runs the command on every
+    /// connection.
+    #[serde(default = "default_cache")]
+    cache: u64,
+    #[serde(skip)]
+    answers: Arc<Mutex<HashMap<String, (Instant, String)>>>,
+}
+
+impl Resolver {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.command.is_empty() || self.command[0].is_empty() {
+            return Err("resolver command must not be empty".into());
+        }
+        if self.timeout == 0 {
+            return Err("resolver timeout must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    /// The command line, for display.
+    pub fn command_line(&self) -> String {
+        self.command.join(" ")
+    }
+
+    /// Returns the ssh server the command picks for the SNI, if any.
+    pub async fn resolve(&self, sni: &str) -> Option<String> {
+        let cache = Duration::from_secs(self.cache);
+        if let Some((at, aim)) = self.answers.lock().unwrap().get(sni) {
+            if at.elapsed() < cache {
+                debug!("[server] resolver answer for {} cached: {}", sni, aim);
+                return Some(aim.clone());
+            }
+        }
+
+        let limit = Duration::from_millis(self.timeout);
+        let aim = match timeout(limit, self.run(sni)).await {
+            Ok(Ok(Some(aim))) => aim,
+            Ok(Ok(None)) => {
+                debug!("[server] resolver has no answer for {}", sni);
+                return None;
+            }
+            Ok(Err(e)) => {
+                warn!("[server] resolver for {} failed: {}", sni, e);
+                return None;
+            }
+            Err(_) => {
+                warn!(
+                    "[server] resolver for {} timed out after {}ms",
+                    sni, self.timeout
+                );
+                return None;
+            }
+        };
+        info!("[server] resolver picked {} for {}", aim, sni);
+
+        let mut answers = self.answers.lock().unwrap();
+        answers.retain(|_, (at, _)| at.elapsed() < cache);
+        if answers.len() >= MAX_CACHED {
+            answers.clear();
+        }
+        if !cache.is_zero() {
+            answers.insert(sni.to_string(), (Instant::now(), aim.clone()));
+        }
+        Some(aim)
+    }
+
+    async fn run(&self, sni: &str) -> Result<Option<String>, String> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            // stop the command when it times out
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("running {} failed: {}", self.command[0], e))?;
+        let mut stdin = child.stdin.take().ok_or("no stdin")?;
+        // a command ignoring its input may exit before reading it
+        let _ = stdin.write_all(format!("{}\n", sni).as_bytes()).await;
+        drop(stdin);
+
+        let output = child.wait_with_output().await.map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(format!("exited with {}", output.status));
+        }
+        let aim = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if aim.is_empty() {
+            return Ok(None);
+        }
+        crate::server::parse_aim(&aim).map(Some)
+    }
+}
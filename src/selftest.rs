@@ -0,0 +1,111 @@
+//! The `selftest` command: runs a server and a client in one process, over
+//! loopback and in front of an echo backend, to check that QUIC works on the
+//! host apart from any ssh server.
+
+use clap::Parser;
+use log::info;
+use quinn::Endpoint;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::{server, Client};
+
+/// Time allowed for the whole round trip, handshake included.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the self-test, printing PASS with the round-trip time or FAIL with the
+/// reason on stdout.
+pub fn run() -> ExitCode {
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            println!("FAIL: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = runtime.block_on(async { timeout(TIMEOUT, round_trip()).await });
+    match result {
+        Ok(Ok(rtt)) => {
+            println!("PASS: round trip in {:.2}ms", rtt.as_secs_f64() * 1000.0);
+            ExitCode::SUCCESS
+        }
+        Ok(Err(e)) => {
+            println!("FAIL: {}", e);
+            ExitCode::FAILURE
+        }
+        Err(_) => {
+            println!("FAIL: no answer within {}s", TIMEOUT.as_secs());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Sends a nonce through the tunnel and returns how long it took to come back.
+async fn round_trip() -> Result<Duration, Box<dyn Error>> {
+    let loopback = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+    let backend = TcpListener::bind(loopback).await?;
+    let backend_addr = backend.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let options = server::Opt::try_parse_from(["server", "--proxy-to", &backend_addr])?;
+    let (endpoint, cert_der) = server::make_server_endpoint(loopback, &options)?;
+    let listen = endpoint.local_addr()?;
+    info!("[selftest] server listening on: {}", listen);
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve(endpoint, options, backend_addr, stopped));
+
+    // the server certificate is self-signed, pin it
+    let fingerprint = ring::digest::digest(&ring::digest::SHA256, &cert_der);
+    let mut tunnel = Client::new(&format!("quic://{}", listen))?
+        .pin(fingerprint.as_ref().try_into()?)
+        .connect()
+        .await?;
+    info!("[selftest] client connected");
+
+    let mut nonce = [0; 16];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "generating the nonce failed")?;
+    let started = Instant::now();
+    tunnel.write_all(&nonce).await?;
+    let mut echoed = [0; 16];
+    tunnel.read_exact(&mut echoed).await?;
+    let rtt = started.elapsed();
+    if echoed != nonce {
+        return Err("the nonce came back altered".into());
+    }
+
+    tunnel.close().await;
+    let _ = stop.send(());
+    server.await?;
+    Ok(rtt)
+}
+
+async fn serve(
+    endpoint: Endpoint,
+    options: server::Opt,
+    backend_addr: String,
+    stopped: oneshot::Receiver<()>,
+) {
+    let route: server::Router = Arc::new(move |_: &str| backend_addr.clone());
+    let shutdown = async {
+        let _ = stopped.await;
+    };
+    let routing = server::Routing::Router(route);
+    server::serve(vec![endpoint], Arc::new(options), routing, None, shutdown).await;
+}
@@ -1,206 +1,2004 @@
-use clap::Parser;
-use quinn::{crypto, Endpoint, ServerConfig, VarInt};
+use clap::builder::{NonEmptyStringValueParser, RangedU64ValueParser};
+use clap::{Parser, ValueEnum};
+use quinn::ConnectionError::{self, ApplicationClosed, LocallyClosed};
+use quinn::{
+    crypto, Connecting, Endpoint, EndpointConfig, ReadError, RecvStream, Runtime, SendStream,
+    ServerConfig, VarInt,
+};
+use socket2::{Domain, Protocol, Socket, Type};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::error::Error;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use std::{net::SocketAddr, sync::Arc};
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::RwLock;
+use std::time::Instant;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::fs::read_to_string;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+#[cfg(not(windows))]
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(windows)]
+use tokio::signal::windows::ctrl_c;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
 
-#[derive(Parser, Debug)]
+use crate::acl::{self, Cidr};
+use crate::audit::{self, AuditLog};
+use crate::idle::Tracked;
+use crate::metrics::{self, METRICS};
+use crate::proxy_protocol;
+use crate::ratelimit::RateLimiter;
+use crate::resolver::Resolver;
+use crate::socket::PlainUdpSocket;
+use crate::throttle::{Throttle, Throttled};
+use crate::traffic::{self, Counted};
+use crate::{close, congestion, datagram, relay, sni, stats};
+
+#[derive(Parser, Debug, Clone)]
 #[clap(name = "server")]
 pub struct Opt {
-    /// Address to listen on
+    /// Address to listen on, may be repeated to serve several at once
     #[clap(long = "listen", short = 'l', default_value = "0.0.0.0:4433")]
-    listen: SocketAddr,
-    /// Address of the ssh server
-    #[clap(long = "proxy-to", short = 'p')]
-    proxy_to: Option<SocketAddr>,
+    listen: Vec<SocketAddr>,
+    /// Accept only IPv6 clients when listening on an IPv6 address, instead of
+    /// both families
+    #[clap(long = "v6only")]
+    v6only: bool,
+    /// Address of the ssh server, either ip:port, host:port or unix:path.
+    /// A `default` entry in the conf file takes precedence over it
+    #[clap(long = "proxy-to", short = 'p', value_parser = parse_aim)]
+    proxy_to: Option<String>,
+    /// TOML file mapping SNIs to ssh server addresses, reloaded on SIGHUP.
+    /// Defaults to ~/.config/quicssh/server.toml, else /etc/quicssh/server.toml,
+    /// if it exists
     #[clap(long = "conf", short = 'F')]
     conf_path: Option<PathBuf>,
+    /// Don't look for a conf file when --conf isn't given
+    #[clap(long = "no-conf", conflicts_with = "conf_path")]
+    no_conf: bool,
+    /// Run the resolver command of the conf file, which is refused otherwise as
+    /// it executes a program for the connecting clients
+    #[clap(long = "allow-resolver")]
+    allow_resolver: bool,
+    /// Check that the conf file parses and every ssh server address resolves,
+    /// print the routing table and exit, without listening
+    #[clap(long = "check-config")]
+    check_config: bool,
+    /// Send a PROXY protocol v2 header with the client address to the ssh server
+    /// before the relayed data
+    #[clap(long = "proxy-protocol")]
+    proxy_protocol: bool,
+    /// Seconds to wait for the connection to the ssh server
+    #[clap(long = "backend-timeout", default_value = "10", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    backend_timeout: u64,
+    /// Times to retry connecting to the ssh server, e.g. while it restarts
+    #[clap(long = "backend-retries", default_value = "0")]
+    backend_retries: u32,
+    /// Milliseconds to wait before the first retry, doubling for each next one
+    #[clap(long = "backend-retry-delay", default_value = "500")]
+    backend_retry_delay: u64,
+    /// File to append a line to for every connection once it ends, with the
+    /// client, SNI, ssh server, bytes, duration and how it ended. As JSON with
+    /// `--log-format json`
+    #[clap(long = "audit-log")]
+    audit_log: Option<PathBuf>,
+    /// Seconds after which a connection is closed whatever its activity, e.g. to
+    /// have clients authenticate again periodically. Off by default
+    #[clap(long = "max-duration", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    max_duration: Option<u64>,
+    /// Disable Nagle's algorithm on the TCP connection to the ssh server, so
+    /// that keystrokes aren't held back to be sent together
+    #[clap(long = "backend-nodelay")]
+    backend_nodelay: bool,
+    /// Seconds without traffic after which TCP keep-alive probes check the
+    /// connection to the ssh server, to notice when it dies. Off by default
+    #[clap(long = "backend-keepalive", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    backend_keepalive: Option<u64>,
+    /// Seconds without data flowing in either direction after which a stream is
+    /// closed, e.g. when the ssh server hangs. Off by default
+    #[clap(long = "stream-timeout", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    stream_timeout: Option<u64>,
+    /// Directory to keep the self-signed certificate in across restarts
+    #[clap(long = "cert-cache")]
+    cert_cache: Option<PathBuf>,
+    /// Name the self-signed certificate is valid for, DNS name or IP address,
+    /// may be repeated. A certificate already in --cert-cache keeps its names
+    #[clap(
+        long = "cert-name",
+        default_value = "localhost",
+        value_parser = crate::client::parse_server_name,
+        conflicts_with = "cert_path"
+    )]
+    cert_names: Vec<String>,
+    /// PEM file of the certificate chain, instead of a self-signed one
+    #[clap(long = "cert", requires = "key_path", conflicts_with = "cert_cache")]
+    cert_path: Option<PathBuf>,
+    /// PEM file of the private key for --cert
+    #[clap(long = "key", requires = "cert_path")]
+    key_path: Option<PathBuf>,
+    /// File of the key the stateless resets are derived from, generated if
+    /// missing. Keeping it across restarts lets the new server tell the clients
+    /// of the old one to give up at once, instead of after their idle timeout
+    #[clap(long = "reset-key")]
+    reset_key: Option<PathBuf>,
+    /// File to append the TLS secrets to, for Wireshark to decrypt a capture
+    /// with. Anyone reading it can decrypt the sessions
+    #[clap(long = "keylog")]
+    keylog: Option<PathBuf>,
+    /// Log the RTT, congestion window, losses and MTU probes of each connection
+    /// every SECS seconds when they change, at the debug level
+    #[clap(long = "trace-transport", value_name = "SECS", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    trace_transport: Option<u64>,
+    /// PEM file of CA certificates to verify client certificates with.
+    /// Clients without a certificate signed by one of them are rejected
+    #[clap(long = "client-ca")]
+    client_ca_path: Option<PathBuf>,
+    /// Seconds to wait for active connections on shutdown before closing them
+    #[clap(long = "shutdown-grace", default_value = "10")]
+    shutdown_grace: u64,
+    /// Address to serve Prometheus metrics on over HTTP
+    #[clap(long = "metrics")]
+    metrics: Option<SocketAddr>,
+    /// Maximum number of connections served at once
+    #[clap(long = "max-connections", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_connections: Option<usize>,
+    /// What to do with new connections over --max-connections
+    #[clap(long = "on-overload", value_enum, default_value = "queue")]
+    on_overload: Overload,
+    /// Maximum number of streams, each relayed to its own ssh connection, a
+    /// client may have open at once. Overrides the conf file [default: 1]
+    #[clap(long = "max-streams", value_parser = RangedU64ValueParser::<u32>::new().range(1..))]
+    max_streams: Option<u32>,
+    /// Idle timeout in seconds, 0 disables it. Overrides the conf file [default: 60]
+    #[clap(long = "idle-timeout")]
+    idle_timeout: Option<u64>,
+    /// Keep-alive interval in milliseconds, 0 disables it. Overrides the conf
+    /// file [default: 1000]
+    #[clap(long = "keep-alive")]
+    keep_alive: Option<u64>,
+    /// Answer each new client with a Retry packet and only commit state once it
+    /// proves it owns its address, against floods with spoofed sources. Costs an
+    /// extra round trip per handshake
+    #[clap(long = "require-address-validation")]
+    require_address_validation: bool,
+    /// Network allowed to connect in CIDR notation, may be repeated. When given,
+    /// any other client is denied
+    #[clap(long = "allow")]
+    allow: Vec<Cidr>,
+    /// Network denied from connecting in CIDR notation, may be repeated. Takes
+    /// precedence over --allow
+    #[clap(long = "deny")]
+    deny: Vec<Cidr>,
+    /// New connections allowed per second from each client IP address. Spoofed
+    /// addresses can get around it unless --require-address-validation is given
+    #[clap(long = "rate", value_parser = parse_rate)]
+    rate: Option<f64>,
+    /// Connections a client IP address may open at once before --rate applies
+    #[clap(long = "burst", default_value = "10", requires = "rate")]
+    burst: u32,
+    /// Bytes per second each connection may send to the client, on top of QUIC's
+    /// own flow control. Not applied in --datagram mode
+    #[clap(long = "rate-limit", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    rate_limit: Option<u64>,
+    /// Size in bytes of the copy buffers, each connection uses two of them
+    #[clap(long = "buffer-size", default_value = "16384", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    buffer_size: usize,
+    /// Bytes a client may send on a stream ahead of what was read, to size for
+    /// the bandwidth times the RTT of the link, e.g. 12500000 for 100 Mbit/s at
+    /// 100 ms [default: 1250000]
+    #[clap(long = "recv-window", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    recv_window: Option<u64>,
+    /// Bytes sent ahead of what the client acknowledged, to size like --recv-window
+    /// [default: 10000000]
+    #[clap(long = "send-window", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    send_window: Option<u64>,
+    /// Disable path MTU discovery, keeping packets at --initial-mtu, e.g. on a
+    /// path that silently drops the larger probes
+    #[clap(long = "no-mtud")]
+    no_mtud: bool,
+    /// Send and receive the packets one by one, without the UDP segmentation
+    /// offload (GSO and GRO) some virtualized or older kernels mishandle. Also
+    /// stops ECN marking
+    #[clap(long = "no-gso")]
+    no_gso: bool,
+    /// UDP payload size in bytes to start with, before MTU discovery raises it
+    /// [default: 1200]
+    #[clap(long = "initial-mtu", value_parser = RangedU64ValueParser::<u16>::new().range(1200..=65527))]
+    initial_mtu: Option<u16>,
+    /// UDP payload size in bytes known to get through the path, which the MTU
+    /// never drops below, e.g. inside a VPN tunnel [default: 1200]
+    #[clap(long = "min-mtu", value_parser = RangedU64ValueParser::<u16>::new().range(1200..=65527))]
+    min_mtu: Option<u16>,
+    /// Congestion controller
+    #[clap(long = "cc", value_enum, default_value = "cubic")]
+    cc: congestion::Controller,
+    /// ALPN protocol identifier, handshakes offering another one are rejected
+    #[clap(long = "alpn", default_value = "quicssh", value_parser = NonEmptyStringValueParser::new())]
+    alpn: String,
+    /// Also serve clients in the experimental datagram mode, which is lossy
+    #[clap(long = "datagram")]
+    datagram: bool,
+    /// Print the line announcing the listening addresses and write the audit
+    /// log as JSON
+    #[clap(skip)]
+    json_output: bool,
+}
+
+/// Generates a self-signed certificate for the names, returning the DER of it and
+/// its private key.
+fn generate_self_signed(names: &[String]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    Ok((cert.serialize_der()?, cert.serialize_private_key_der()))
+}
+
+/// Loads the self-signed certificate from the cache directory,
+/// generating and saving it there if it doesn't exist yet.
+fn load_or_generate_self_signed(
+    dir: &Path,
+    names: &[String],
+) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let cert_path = dir.join("cert.der");
+    let key_path = dir.join("key.der");
+    if cert_path.exists() && key_path.exists() {
+        info!("[server] loading certificate from: {}", dir.display());
+        return Ok((std::fs::read(cert_path)?, std::fs::read(key_path)?));
+    }
+
+    let (cert_der, key_der) = generate_self_signed(names)?;
+    std::fs::create_dir_all(dir)?;
+    let mut key_file = std::fs::OpenOptions::new();
+    key_file.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut key_file, 0o600);
+    key_file.open(key_path)?.write_all(&key_der)?;
+    std::fs::write(cert_path, &cert_der)?;
+    info!("[server] saved generated certificate to: {}", dir.display());
+    Ok((cert_der, key_der))
 }
 
-/// Returns default server configuration along with its certificate.
-fn configure_server() -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let cert_der = cert.serialize_der().unwrap();
-    let priv_key = cert.serialize_private_key_der();
-    let priv_key = rustls::PrivateKey(priv_key);
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
+/// Returns server configuration along with its certificate.
+///
+/// The certificate is loaded from `--cert`/`--key` if given,
+/// otherwise a self-signed one is used, cached in `--cert-cache` if given.
+fn configure_server(options: &Opt) -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
+    if let (Some(min_mtu), Some(initial_mtu)) = (options.min_mtu, options.initial_mtu) {
+        if min_mtu > initial_mtu {
+            return Err(format!(
+                "--min-mtu {} must not exceed --initial-mtu {}",
+                min_mtu, initial_mtu
+            )
+            .into());
+        }
+    }
+    let (cert_chain, priv_key) = match (&options.cert_path, &options.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            info!("[server] loading certificate from: {}", cert_path.display());
+            (
+                crate::pem::load_certs(cert_path)?,
+                crate::pem::load_private_key(key_path)?,
+            )
+        }
+        (None, None) => {
+            let (cert_der, key_der) = match &options.cert_cache {
+                Some(dir) => load_or_generate_self_signed(dir, &options.cert_names)?,
+                None => generate_self_signed(&options.cert_names)?,
+            };
+            (
+                vec![rustls::Certificate(cert_der)],
+                rustls::PrivateKey(key_der),
+            )
+        }
+        _ => return Err("--cert and --key must be given together".into()),
+    };
+    let cert_der = cert_chain[0].0.clone();
+
+    let client_verifier = match &options.client_ca_path {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in crate::pem::load_certs(path)? {
+                roots.add(&cert)?;
+            }
+            rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed()
+        }
+        None => rustls::server::NoClientAuth::boxed(),
+    };
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(cert_chain, priv_key)?;
+    // accept 0-RTT data from resuming clients, which rustls only allows with the
+    // default stateful session cache rather than stateless tickets; QUIC only
+    // permits 0 or u32::MAX here, so the early data can't be capped, and an
+    // attacker on the path can replay it (see "0-RTT replay" in the README)
+    server_crypto.max_early_data_size = u32::MAX;
+    if let Some(path) = &options.keylog {
+        server_crypto.key_log = crate::keylog::open(path, "server")
+            .map_err(|e| format!("opening key log {} failed: {}", path.display(), e))?;
+    }
+    server_crypto.alpn_protocols = vec![options.alpn.as_bytes().to_vec(), sni::alpn(&options.alpn)];
+    if options.datagram {
+        server_crypto
+            .alpn_protocols
+            .push(datagram::alpn(&options.alpn));
+    }
 
-    let mut server_config = ServerConfig::with_single_cert(cert_chain, priv_key)?;
+    let mut server_config = ServerConfig::with_crypto(Arc::new(server_crypto));
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
+    // granted to the connections sending a route header only, see `serve`
     transport_config.max_concurrent_uni_streams(0_u8.into());
-    transport_config.max_idle_timeout(Some(VarInt::from_u32(60_000).into()));
-    transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(1)));
-    #[cfg(any(windows, os = "linux"))]
+    transport_config.max_concurrent_bidi_streams(options.max_streams.unwrap_or(1).into());
+    transport_config.max_idle_timeout(match options.idle_timeout.unwrap_or(60) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs).try_into()?),
+    });
+    transport_config.keep_alive_interval(match options.keep_alive.unwrap_or(1000) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    });
+    transport_config
+        .datagram_receive_buffer_size(options.datagram.then_some(datagram::RECEIVE_BUFFER_SIZE));
+    if let Some(window) = options.recv_window {
+        transport_config.stream_receive_window(VarInt::from_u64(window)?);
+        transport_config.receive_window(VarInt::from_u64(window)?);
+    }
+    if let Some(window) = options.send_window {
+        transport_config.send_window(window);
+    }
+    options.cc.apply(transport_config);
+    #[cfg(any(windows, target_os = "linux"))]
     transport_config.mtu_discovery_config(Some(quinn::MtuDiscoveryConfig::default()));
+    if options.no_mtud {
+        transport_config.mtu_discovery_config(None);
+    }
+    if let Some(mtu) = options.initial_mtu {
+        transport_config.initial_mtu(mtu);
+    }
+    if let Some(mtu) = options.min_mtu {
+        transport_config.min_mtu(mtu);
+    }
+    server_config.use_retry(options.require_address_validation);
 
     Ok((server_config, cert_der))
 }
 
+/// Creates the UDP socket for the server endpoint.
+fn make_server_socket(bind_addr: SocketAddr, options: &Opt) -> Result<UdpSocket, Box<dyn Error>> {
+    let socket = Socket::new(
+        Domain::for_address(bind_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    // platforms disagree on the default, so a [::] listener serves IPv4 too unless told otherwise
+    if bind_addr.is_ipv6() {
+        socket.set_only_v6(options.v6only)?;
+    }
+    socket.bind(&bind_addr.into()).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => format!(
+            "binding {} denied: {}. Listen on a port above 1023, or allow the binary to \
+             bind privileged ports with `setcap cap_net_bind_service=+ep`",
+            bind_addr, e
+        ),
+        _ => format!("binding {} failed: {}", bind_addr, e),
+    })?;
+    Ok(socket.into())
+}
+
 #[allow(unused)]
-pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
-    let (server_config, server_cert) = configure_server()?;
-    let endpoint = Endpoint::server(server_config, bind_addr)?;
+pub fn make_server_endpoint(
+    bind_addr: SocketAddr,
+    options: &Opt,
+) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
+    let (server_config, server_cert) = configure_server(options)?;
+    let endpoint_config = configure_endpoint(options)?;
+    let runtime = quinn::default_runtime().ok_or("no async runtime found")?;
+    let socket = make_server_socket(bind_addr, options)?;
+    let endpoint = new_server_endpoint(socket, endpoint_config, server_config, runtime, options)?;
     Ok((endpoint, server_cert))
 }
 
+/// Returns the endpoint configuration, with the stateless reset key of
+/// `--reset-key` if given.
+fn configure_endpoint(options: &Opt) -> Result<EndpointConfig, Box<dyn Error>> {
+    match &options.reset_key {
+        Some(path) => Ok(EndpointConfig::new(crate::reset_key::load_or_generate(
+            path,
+        )?)),
+        None => Ok(EndpointConfig::default()),
+    }
+}
+
+/// Creates a server endpoint on the socket, sending the packets one by one
+/// with --no-gso.
+fn new_server_endpoint(
+    socket: UdpSocket,
+    endpoint_config: EndpointConfig,
+    server_config: ServerConfig,
+    runtime: Arc<dyn Runtime>,
+    options: &Opt,
+) -> io::Result<Endpoint> {
+    if options.no_gso {
+        let socket = PlainUdpSocket::new(socket)?;
+        Endpoint::new_with_abstract_socket(endpoint_config, Some(server_config), socket, runtime)
+    } else {
+        Endpoint::new(endpoint_config, Some(server_config), socket, runtime)
+    }
+}
+
+/// Takes the UDP sockets passed by systemd socket activation, if the server
+/// was started that way.
+#[cfg(unix)]
+fn activated_sockets() -> Result<Option<Vec<UdpSocket>>, Box<dyn Error>> {
+    use std::os::unix::io::FromRawFd;
+    // the first passed descriptor, after stdin, stdout and stderr
+    const LISTEN_FDS_START: i32 = 3;
+
+    let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let listen_fds = std::env::var("LISTEN_FDS");
+    // like sd_listen_fds(1), so that the resolver and other children don't take them for theirs
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let count: i32 = match listen_fds {
+        Ok(count) if for_us => count
+            .parse()
+            .map_err(|e| format!("invalid LISTEN_FDS {}: {}", count, e))?,
+        _ => return Ok(None),
+    };
+    if count == 0 {
+        return Ok(None);
+    }
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd hands the descriptors from 3 on over to this process
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if socket.r#type()? != Type::DGRAM {
+            return Err(
+                format!("socket passed by systemd as fd {} is not a UDP socket", fd).into(),
+            );
+        }
+        sockets.push(socket.into());
+    }
+    Ok(Some(sockets))
+}
+
+#[cfg(not(unix))]
+fn activated_sockets() -> Result<Option<Vec<UdpSocket>>, Box<dyn Error>> {
+    Ok(None)
+}
+
+/// Creates an endpoint for each socket passed by systemd, else for each address
+/// to listen on, sharing one server config so that they present the same
+/// certificate. Fails with every address that couldn't be bound.
+fn make_server_endpoints(options: &Opt) -> Result<Vec<Endpoint>, Box<dyn Error>> {
+    let (server_config, _) = configure_server(options)?;
+    let endpoint_config = configure_endpoint(options)?;
+    let runtime = quinn::default_runtime().ok_or("no async runtime found")?;
+    let sockets: Vec<Result<UdpSocket, Box<dyn Error>>> = match activated_sockets()? {
+        Some(sockets) => {
+            info!("[server] socket activated by systemd, ignoring --listen");
+            sockets.into_iter().map(Ok).collect()
+        }
+        None => options
+            .listen
+            .iter()
+            .map(|&addr| make_server_socket(addr, options))
+            .collect(),
+    };
+    let mut endpoints = Vec::new();
+    let mut errors = Vec::new();
+    for socket in sockets {
+        let endpoint = socket.and_then(|socket| {
+            info!("[server] listening on: {}", socket.local_addr()?);
+            Ok(new_server_endpoint(
+                socket,
+                endpoint_config.clone(),
+                server_config.clone(),
+                runtime.clone(),
+                options,
+            )?)
+        });
+        match endpoint {
+            Ok(endpoint) => endpoints.push(endpoint),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join(", ").into());
+    }
+    Ok(endpoints)
+}
+
+/// Checks that a proxy aim is either `host:port` or `unix:path`.
+pub(crate) fn parse_aim(aim: &str) -> Result<String, String> {
+    if let Some(path) = aim.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err("unix socket path is empty".into());
+        }
+        return Ok(aim.to_string());
+    }
+    match aim.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(aim.to_string())
+        }
+        _ => Err(format!(
+            "invalid address {}, expected host:port or unix:path",
+            aim
+        )),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        Ok(_) => Err("rate must be a positive number".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Overload {
+    // wait for a slot before serving the connection
+    Queue,
+    // close the connection as busy
+    Reject,
+}
+
+/// A proxy entry of the conf file: the ssh server alone, or a table of it as
+/// `aim` with settings overriding the flags for the connections of the entry.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum ProxyEntry {
+    Aim(String),
+    Tuned {
+        aim: String,
+        #[serde(flatten)]
+        tuning: Tuning,
+    },
+}
+
+impl ProxyEntry {
+    fn aim(&self) -> &String {
+        match self {
+            ProxyEntry::Aim(aim) | ProxyEntry::Tuned { aim, .. } => aim,
+        }
+    }
+
+    fn tuning(&self) -> Tuning {
+        match self {
+            ProxyEntry::Aim(_) => Tuning::default(),
+            ProxyEntry::Tuned { tuning, .. } => tuning.clone(),
+        }
+    }
+}
+
+/// The settings a proxy entry may override for its connections.
+///
+/// quinn settles the idle timeout, the keep-alive and the send and stream
+/// windows in the handshake, before the SNI is known, so those are set for the
+/// whole server only. The entries naming them are refused.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Tuning {
+    stream_timeout: Option<u64>,
+    max_streams: Option<u32>,
+    // only the window of the whole connection, each stream keeps its own
+    recv_window: Option<u64>,
+    idle_timeout: Option<toml::Value>,
+    keep_alive: Option<toml::Value>,
+    send_window: Option<toml::Value>,
+}
+
+impl Tuning {
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("idle-timeout", &self.idle_timeout),
+            ("keep-alive", &self.keep_alive),
+            ("send-window", &self.send_window),
+        ] {
+            if value.is_some() {
+                return Err(format!(
+                    "{} is settled in the handshake before the SNI is known, set it at the top of the conf file",
+                    name
+                ));
+            }
+        }
+        if self.max_streams == Some(0) {
+            return Err("max-streams must be at least 1".into());
+        }
+        if let Some(window) = self.recv_window {
+            VarInt::from_u64(window).map_err(|_| "recv-window is too large")?;
+        }
+        Ok(())
+    }
+
+    /// Applies the settings to the connection, returning the options to serve it with.
+    fn apply(&self, connection: &quinn::Connection, options: &Arc<Opt>) -> Arc<Opt> {
+        // a lower limit only takes effect as the streams already allowed close
+        if let Some(max_streams) = self.max_streams {
+            connection.set_max_concurrent_bi_streams(max_streams.into());
+        }
+        if let Some(window) = self.recv_window {
+            connection.set_receive_window(VarInt::from_u64(window).unwrap_or(VarInt::MAX));
+        }
+        match self.stream_timeout {
+            Some(stream_timeout) => {
+                let mut options = Opt::clone(options);
+                options.stream_timeout = Some(stream_timeout);
+                Arc::new(options)
+            }
+            None => options.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Tuning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let settings = [
+            ("stream-timeout", self.stream_timeout),
+            ("max-streams", self.max_streams.map(u64::from)),
+            ("recv-window", self.recv_window),
+        ];
+        let set: Vec<String> = settings
+            .iter()
+            .filter_map(|(name, value)| Some(format!("{} = {}", name, (*value)?)))
+            .collect();
+        write!(f, "{}", set.join(", "))
+    }
+}
+
+/// The conf file: the proxy entries by client certificate common name and by
+/// SNI, the resolver command for the other SNIs, and transport settings which
+/// the flags of the same name override. The transport settings are only read at
+/// startup, a reload changes the routing alone.
 #[derive(Deserialize, Debug)]
-struct ServerConf {
-    proxy: HashMap<String, SocketAddr>,
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ServerConf {
+    #[serde(default)]
+    proxy: HashMap<String, ProxyEntry>,
+    /// Taking precedence over `proxy` for the clients with a certificate.
+    #[serde(default)]
+    proxy_by_cn: HashMap<String, ProxyEntry>,
+    resolver: Option<Resolver>,
+    idle_timeout: Option<u64>,
+    keep_alive: Option<u64>,
+    max_streams: Option<u32>,
 }
 impl ServerConf {
     fn new() -> Self {
         ServerConf {
-            proxy: HashMap::<String, SocketAddr>::new(),
+            proxy: HashMap::new(),
+            proxy_by_cn: HashMap::new(),
+            resolver: None,
+            idle_timeout: None,
+            keep_alive: None,
+            max_streams: None,
         }
     }
-}
 
-#[tokio::main]
-pub async fn run(options: Opt) -> Result<(), Box<dyn Error>> {
-    let conf: ServerConf = match options.conf_path {
-        Some(path) => {
-            info!("[server] importing conf file: {}", path.display());
-            toml::from_str(&(read_to_string(path).await?))?
+    /// Loads the conf file, refusing a resolver command unless allowed.
+    async fn load(path: &Path, allow_resolver: bool) -> Result<Self, Box<dyn Error>> {
+        info!("[server] importing conf file: {}", path.display());
+        let conf: ServerConf = toml::from_str(&(read_to_string(path).await?))?;
+        for (sni, entry) in &conf.proxy {
+            parse_aim(entry.aim())
+                .and_then(|_| entry.tuning().validate())
+                .map_err(|e| format!("proxy entry {}: {}", sni, e))?;
+        }
+        for (cn, entry) in &conf.proxy_by_cn {
+            parse_aim(entry.aim())
+                .and_then(|_| entry.tuning().validate())
+                .map_err(|e| format!("proxy-by-cn entry {}: {}", cn, e))?;
+        }
+        if let Some(resolver) = &conf.resolver {
+            resolver.validate()?;
+            if !allow_resolver {
+                return Err(
+                    "the conf file sets a resolver command, pass --allow-resolver to run it".into(),
+                );
+            }
         }
+        if conf.max_streams == Some(0) {
+            return Err("max-streams must be at least 1".into());
+        }
+        Ok(conf)
+    }
+
+    /// Returns the SNI's own entry: the exact entry, else the wildcard entry with
+    /// the longest matching suffix.
+    fn matched_entry(&self, sni: &str) -> Option<ProxyEntry> {
+        self.proxy
+            .get(sni)
+            .or_else(|| self.wildcard_entry(sni))
+            .cloned()
+    }
+
+    /// Finds the `*.example.com` style entry with the longest suffix of the SNI.
+    fn wildcard_entry(&self, sni: &str) -> Option<&ProxyEntry> {
+        self.proxy
+            .iter()
+            .filter_map(|(pattern, entry)| {
+                let suffix = pattern.strip_prefix('*')?;
+                let matched =
+                    suffix.starts_with('.') && sni.len() > suffix.len() && sni.ends_with(suffix);
+                matched.then_some((suffix.len(), entry))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, entry)| entry)
+    }
+}
+
+impl Opt {
+    /// Prints the line announcing the listening addresses and writes the audit
+    /// log as JSON, to match `--log-format json`.
+    pub fn set_json_output(&mut self, json: bool) {
+        self.json_output = json;
+    }
+}
+
+/// Checks that an ssh server address resolves, or that the unix socket exists.
+async fn check_aim(aim: &str) -> Result<(), String> {
+    if let Some(path) = aim.strip_prefix("unix:") {
+        return match tokio::fs::metadata(path).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    match lookup_host(aim).await.map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err("no address found".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Prints the routing table of the conf file, failing with every entry whose
+/// ssh server doesn't resolve.
+async fn check_config(options: &Opt) -> Result<(), Box<dyn Error>> {
+    let conf = match &options.conf_path {
+        Some(path) => ServerConf::load(path, options.allow_resolver).await?,
         None => ServerConf::new(),
     };
-
-    let default_proxy = match conf.proxy.get("default") {
-        Some(sock) => sock.clone(),
-        None => options
-            .proxy_to
-            .unwrap_or(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22)),
+    let mut routes: Vec<(String, &ProxyEntry)> = conf
+        .proxy
+        .iter()
+        .filter(|(sni, _)| *sni != "default")
+        .map(|(sni, entry)| (sni.clone(), entry))
+        .collect();
+    routes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut cn_routes: Vec<(String, &ProxyEntry)> = conf
+        .proxy_by_cn
+        .iter()
+        .map(|(cn, entry)| (format!("cn:{}", cn), entry))
+        .collect();
+    cn_routes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    // the order they are matched in
+    cn_routes.append(&mut routes);
+    let routes = cn_routes;
+    let local = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22).to_string();
+    let default = match (
+        conf.proxy.get("default").map(ProxyEntry::aim),
+        &options.proxy_to,
+    ) {
+        (Some(aim), _) => (aim, "conf file"),
+        (None, Some(aim)) => (aim, "--proxy-to"),
+        (None, None) => (&local, "built in"),
     };
-    info!("[server] default proxy aim: {}", default_proxy);
 
-    let (endpoint, _) = make_server_endpoint(options.listen).unwrap();
-    info!("[server] listening on: {}", options.listen);
-    // accept a single connection
-    loop {
-        let incoming_conn = match endpoint.accept().await {
-            Some(conn) => conn,
-            None => {
-                continue;
-            }
+    let mut problems = Vec::new();
+    for (sni, entry) in routes {
+        let aim = entry.aim();
+        let tuning = match entry.tuning().to_string() {
+            tuning if tuning.is_empty() => tuning,
+            tuning => format!(" [{}]", tuning),
         };
-        let conn = match incoming_conn.await {
-            Ok(conn) => conn,
+        match check_aim(aim).await {
+            Ok(()) => println!("{} -> {}{}", sni, aim, tuning),
             Err(e) => {
-                error!("[server] accept connection error: {}", e);
-                continue;
+                println!("{} -> {}{} (error: {})", sni, aim, tuning, e);
+                problems.push(format!("{}: {}", sni, e));
             }
-        };
-
-        let sni = conn
-            .handshake_data()
-            .unwrap()
-            .downcast::<crypto::rustls::HandshakeData>()
-            .unwrap()
-            .server_name
-            .unwrap_or(conn.remote_address().ip().to_string());
-        let proxy_to = conf.proxy.get(&sni).unwrap_or(&default_proxy).clone();
-        info!(
-            "[server] connection accepted: ({}, {}) -> {}",
-            conn.remote_address(),
-            sni,
-            proxy_to
-        );
-        tokio::spawn(async move {
-            handle_connection(proxy_to, conn).await;
-        });
-        // Dropping all handles associated with a connection implicitly closes it
+        }
+    }
+    if let Some(resolver) = &conf.resolver {
+        println!("other -> resolver {}", resolver.command_line());
+    }
+    let (aim, source) = default;
+    match check_aim(aim).await {
+        Ok(()) => println!("default -> {} ({})", aim, source),
+        Err(e) => {
+            println!("default -> {} ({}, error: {})", aim, source, e);
+            problems.push(format!("default: {}", e));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(format!("invalid conf: {}", problems.join(", ")).into());
     }
+    Ok(())
 }
 
-async fn handle_connection(proxy_for: SocketAddr, connection: quinn::Connection) {
-    let ssh_stream = TcpStream::connect(proxy_for).await;
-    let ssh_conn = match ssh_stream {
-        Ok(conn) => conn,
+/// Re-reads the conf file on every SIGHUP, keeping the old conf if it fails.
+#[cfg(not(windows))]
+async fn reload_conf_on_hangup(path: PathBuf, allow_resolver: bool, conf: Arc<RwLock<ServerConf>>) {
+    let mut stream = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
         Err(e) => {
-            error!("[server] connect to ssh error: {}", e);
+            error!("[server] create signal stream error: {}", e);
             return;
         }
     };
 
-    info!("[server] ssh connection established");
+    while stream.recv().await.is_some() {
+        info!("[server] got signal HUP");
+        let loaded = ServerConf::load(&path, allow_resolver)
+            .await
+            .map_err(|e| e.to_string());
+        match loaded {
+            Ok(new_conf) => {
+                *conf.write().unwrap() = new_conf;
+                info!("[server] conf file reloaded");
+            }
+            Err(e) => {
+                error!(
+                    "[server] reloading conf file error, keep the old one: {}",
+                    e
+                );
+            }
+        }
+    }
+}
 
-    let (mut quinn_send, mut quinn_recv) = match connection.accept_bi().await {
-        Ok(stream) => stream,
+/// Logs the bytes relayed so far by every active connection on each SIGUSR1.
+#[cfg(not(windows))]
+async fn log_traffic_on_user_signal() {
+    let mut stream = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
         Err(e) => {
-            error!("[server] open quic stream error: {}", e);
+            error!("[server] create signal stream error: {}", e);
             return;
         }
     };
 
-    let (mut ssh_recv, mut ssh_write) = tokio::io::split(ssh_conn);
+    while stream.recv().await.is_some() {
+        let snapshot = traffic::snapshot();
+        info!(
+            "[server] got signal USR1, {} active connections",
+            snapshot.len()
+        );
+        for line in snapshot {
+            info!("[server]   {}", line);
+        }
+    }
+}
 
-    let recv_thread = async move {
-        let mut buf = [0; 2048];
-        loop {
-            match ssh_recv.read(&mut buf).await {
-                Ok(n) => {
-                    if n == 0 {
-                        continue;
-                    }
-                    debug!("[server] recv data from ssh server {} bytes", n);
-                    match quinn_send.write_all(&buf[..n]).await {
-                        Ok(_) => (),
-                        Err(e) => {
-                            error!("[server] writing to quic stream error: {}", e);
-                            return;
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("[server] reading from ssh server error: {}", e);
-                    return;
+/// Returns the first of `$XDG_CONFIG_HOME/quicssh/server.toml` and
+/// `/etc/quicssh/server.toml` which exists.
+fn default_conf_path() -> Option<PathBuf> {
+    crate::client::default_conf_path("server.toml")
+        .into_iter()
+        .chain([PathBuf::from("/etc/quicssh/server.toml")])
+        .find(|path| path.exists())
+}
+
+/// Runs the server on a runtime of its own, see `run_inner`.
+pub fn run(options: Opt) -> Result<(), Box<dyn Error>> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run_inner(options))
+}
+
+/// Accepts connections and proxies them to the ssh servers until a shutdown signal.
+pub async fn run_inner(mut options: Opt) -> Result<(), Box<dyn Error>> {
+    let discovered = options.conf_path.is_none() && !options.no_conf;
+    if discovered {
+        options.conf_path = default_conf_path();
+    }
+    if options.check_config {
+        return check_config(&options).await;
+    }
+    let conf = match &options.conf_path {
+        Some(path) => ServerConf::load(path, options.allow_resolver).await?,
+        None => ServerConf::new(),
+    };
+    options.idle_timeout = options.idle_timeout.or(conf.idle_timeout);
+    options.keep_alive = options.keep_alive.or(conf.keep_alive);
+    options.max_streams = options.max_streams.or(conf.max_streams);
+    if !conf.proxy_by_cn.is_empty() && options.client_ca_path.is_none() {
+        warn!("[server] the conf file routes by client certificate, but clients only send one with --client-ca");
+    }
+    let options = Arc::new(options);
+    let conf = Arc::new(RwLock::new(conf));
+    #[cfg(not(windows))]
+    if let Some(path) = &options.conf_path {
+        tokio::spawn(reload_conf_on_hangup(
+            path.clone(),
+            options.allow_resolver,
+            conf.clone(),
+        ));
+    }
+    #[cfg(not(windows))]
+    tokio::spawn(log_traffic_on_user_signal());
+
+    // the conf `default` entry, else --proxy-to, else the local ssh server
+    let fallback_proxy = options
+        .proxy_to
+        .clone()
+        .unwrap_or(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22).to_string());
+    let conf_default = conf
+        .read()
+        .unwrap()
+        .proxy
+        .get("default")
+        .map(|entry| entry.aim().clone());
+    if let (Some(conf_default), Some(proxy_to)) = (&conf_default, &options.proxy_to) {
+        match (&options.conf_path, discovered) {
+            (Some(path), true) => warn!(
+                "[server] the conf file found at {} overrides --proxy-to {} with its default entry {}, pass --no-conf to ignore the file",
+                path.display(), proxy_to, conf_default
+            ),
+            _ => warn!(
+                "[server] both the conf file and --proxy-to set the default proxy aim, using the conf file's {} over {}",
+                conf_default, proxy_to
+            ),
+        }
+    }
+    info!(
+        "[server] default proxy aim: {}",
+        conf_default.unwrap_or(fallback_proxy.clone())
+    );
+
+    let endpoints = make_server_endpoints(&options)?;
+    if let Some(addr) = options.metrics {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("binding metrics address {} failed: {}", addr, e))?;
+        info!("[server] serving metrics on: {}", addr);
+        tokio::spawn(metrics::serve(listener));
+    }
+    let audit = match &options.audit_log {
+        Some(path) => Some(Arc::new(
+            AuditLog::open(path, options.json_output)
+                .map_err(|e| format!("opening audit log {} failed: {}", path.display(), e))?,
+        )),
+        None => None,
+    };
+    announce_ready(&endpoints, options.json_output);
+    let routing = Routing::Conf(conf, fallback_proxy);
+    serve(endpoints, options, routing, audit, create_shutdown_signal()).await;
+    Ok(())
+}
+
+/// Picks the ssh server to proxy a connection to by its SNI, as `host:port` or
+/// `unix:path`.
+pub type Router = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Where the ssh server of a connection comes from.
+#[derive(Clone)]
+pub(crate) enum Routing {
+    /// The router of the library API.
+    Router(Router),
+    /// The entry of the client certificate's common name in the conf file,
+    /// else the entry of the SNI, else the answer of the resolver command, else
+    /// the `default` entry, else the fallback. Once the conf file routes by
+    /// common name, a client with a certificate is never routed by its SNI.
+    Conf(Arc<RwLock<ServerConf>>, String),
+}
+
+impl Routing {
+    /// Returns the ssh server for the client certificate's common name if any,
+    /// else for the SNI, and the settings of its entry. `cert` is `None` without
+    /// a client certificate, and `Some(None)` for one without a readable common
+    /// name.
+    async fn route(&self, sni: &str, cert: Option<Option<&str>>) -> (String, Tuning) {
+        let (conf, fallback) = match self {
+            Routing::Router(route) => return (route(sni), Tuning::default()),
+            Routing::Conf(conf, fallback) => (conf, fallback),
+        };
+        let (by_cn, routes_by_cn, matched, resolver, default) = {
+            let conf = conf.read().unwrap();
+            (
+                cert.flatten()
+                    .and_then(|cn| conf.proxy_by_cn.get(cn).cloned()),
+                !conf.proxy_by_cn.is_empty(),
+                conf.matched_entry(sni),
+                conf.resolver.clone(),
+                conf.proxy.get("default").cloned(),
+            )
+        };
+        if let Some(entry) = by_cn {
+            debug!(
+                "[server] routing by client certificate: {}",
+                cert.flatten().unwrap_or_default()
+            );
+            return (entry.aim().clone(), entry.tuning());
+        }
+        // a certificate holder mustn't reach another entry by picking the SNI
+        if let (Some(cn), true) = (cert, routes_by_cn) {
+            warn!(
+                "[server] no proxy-by-cn entry for client certificate: {}",
+                cn.unwrap_or("(no common name)")
+            );
+            return match default {
+                Some(entry) => (entry.aim().clone(), entry.tuning()),
+                None => (fallback.clone(), Tuning::default()),
+            };
+        }
+        if let Some(entry) = matched {
+            return (entry.aim().clone(), entry.tuning());
+        }
+        if let Some(aim) = match resolver {
+            Some(resolver) => resolver.resolve(sni).await,
+            None => None,
+        } {
+            return (aim, Tuning::default());
+        }
+        match default {
+            Some(entry) => (entry.aim().clone(), entry.tuning()),
+            None => (fallback.clone(), Tuning::default()),
+        }
+    }
+}
+
+/// Accepts connections on the endpoints and proxies them to the ssh servers the
+/// router picks, until `shutdown` completes and the active connections drain.
+/// Each connection ends with an entry in the audit log, if any.
+pub(crate) async fn serve(
+    endpoints: Vec<Endpoint>,
+    options: Arc<Opt>,
+    routing: Routing,
+    audit: Option<Arc<AuditLog>>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut rate_limiter = options
+        .rate
+        .map(|rate| RateLimiter::new(rate, options.burst));
+    let limit = options
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    tokio::pin!(shutdown);
+    // one task per endpoint feeds the incoming connections, with the address
+    // they came in on, to the single loop below
+    let (incoming_send, mut incoming_recv) = mpsc::channel(1);
+    for endpoint in &endpoints {
+        let endpoint = endpoint.clone();
+        let incoming_send = incoming_send.clone();
+        tokio::spawn(async move {
+            let Ok(listen) = endpoint.local_addr() else {
+                return;
+            };
+            while let Some(conn) = endpoint.accept().await {
+                if incoming_send.send((conn, listen)).await.is_err() {
+                    break;
                 }
             }
+        });
+    }
+    drop(incoming_send);
+    // accept connections from every endpoint until shutdown, each in its own task
+    loop {
+        let (incoming_conn, listen) = tokio::select! {
+            incoming = incoming_recv.recv() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        let remote = incoming_conn.remote_address();
+        // refused by the address alone, before any routing
+        if !acl::allowed(&options.allow, &options.deny, remote.ip()) {
+            info!("[server] access denied: {}", remote);
+            refuse(
+                incoming_conn,
+                close::ACCESS_DENIED,
+                b"access denied",
+                audit.clone(),
+            );
+            continue;
+        }
+        if let Some(rate_limiter) = &mut rate_limiter {
+            if !rate_limiter.allow(remote.ip()) {
+                info!("[server] rate limit exceeded: {}", remote);
+                refuse(
+                    incoming_conn,
+                    close::RATE_LIMITED,
+                    b"rate limited",
+                    audit.clone(),
+                );
+                continue;
+            }
         }
-    };
 
-    let write_thread = async move {
-        let mut buf = [0; 2048];
-        loop {
-            match quinn_recv.read(&mut buf).await {
-                Ok(None) => {
-                    continue;
+        let options = options.clone();
+        let limit = limit.clone();
+        let routing = routing.clone();
+        let audit = audit.clone();
+        // in a task of its own, so that a stalled handshake holds up no other
+        tokio::spawn(async move {
+            let conn = match incoming_conn.await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log_handshake_failure(remote, &e);
+                    METRICS.handshake_failures.fetch_add(1, Relaxed);
+                    return;
                 }
-                Ok(Some(n)) => {
-                    debug!("[server] recv data from quic stream {} bytes", n);
-                    if n == 0 {
-                        continue;
+            };
+            let accepted = Instant::now();
+            METRICS.connections.fetch_add(1, Relaxed);
+            let sni = server_name(&conn);
+            // before waiting for the route header and routing, so that
+            // --max-connections bounds those and the resolver commands too
+            let _permit = match limit {
+                Some(limit) => match acquire_slot(limit, &conn, options.on_overload).await {
+                    Some(permit) => Some(permit),
+                    None => {
+                        audit::finish(audit.as_deref(), &conn, &sni, None, accepted);
+                        return;
                     }
-                    match ssh_write.write_all(&buf[..n]).await {
-                        Ok(_) => (),
-                        Err(e) => {
-                            error!("[server] writing to ssh server error: {}", e);
-                            return;
-                        }
+                },
+                None => None,
+            };
+            // the client sent a decoy SNI, and the name to route by once encrypted
+            let sni = if sni::negotiated(&conn, &options.alpn) {
+                conn.set_max_concurrent_uni_streams(1_u8.into());
+                let header = timeout(sni::ROUTE_TIMEOUT, sni::recv_route(&conn))
+                    .await
+                    .unwrap_or_else(|_| Err("timed out".into()));
+                match header {
+                    Ok(name) => {
+                        debug!("[server] route header {} behind SNI {}", name, sni);
+                        name
+                    }
+                    Err(e) => {
+                        info!(
+                            "[server] no route header from {}: {}",
+                            conn.remote_address(),
+                            e
+                        );
+                        audit::close(&conn, close::NO_ROUTE, b"no route header");
+                        audit::finish(audit.as_deref(), &conn, &sni, None, accepted);
+                        return;
                     }
                 }
-                Err(e) => {
-                    error!("[server] reading from quic client error: {}", e);
-                    return;
-                }
+            } else {
+                sni
+            };
+            let cn = client_common_name(&conn);
+            let (proxy_to, tuning) = routing.route(&sni, cn.as_ref().map(Option::as_deref)).await;
+            info!(
+                remote:% = conn.remote_address(), sni:% = sni;
+                "[server] connection accepted: ({}, {}) -> {}",
+                conn.remote_address(),
+                sni,
+                proxy_to
+            );
+            let alpn = stats::alpn(&conn);
+            info!(remote:% = conn.remote_address(), alpn:% = alpn; "[server] negotiated ALPN: {}", alpn);
+            let options = tuning.apply(&conn, &options);
+            let registration = traffic::register(&conn, &sni, &proxy_to);
+            handle_connection(&sni, &proxy_to, &conn, listen, options).await;
+            let routed = (proxy_to.as_str(), registration.counters().as_ref());
+            audit::finish(audit.as_deref(), &conn, &sni, Some(routed), accepted);
+        });
+        // Dropping all handles associated with a connection implicitly closes it
+    }
+
+    // refuse new connections while draining the active ones
+    for endpoint in &endpoints {
+        endpoint.set_server_config(None);
+    }
+    let grace = Duration::from_secs(options.shutdown_grace);
+    info!(
+        "[server] waiting up to {}s for active connections",
+        grace.as_secs()
+    );
+    let drained = async {
+        for endpoint in &endpoints {
+            endpoint.wait_idle().await;
+        }
+    };
+    if timeout(grace, drained).await.is_err() {
+        info!("[server] grace period elapsed, closing remaining connections");
+    }
+    for endpoint in &endpoints {
+        endpoint.close(close::SERVER_SHUTDOWN, b"server shutdown");
+        endpoint.wait_idle().await;
+    }
+
+    info!("[server] exit server");
+}
+
+/// Returns the SNI the client sent, else its IP address.
+fn server_name(connection: &quinn::Connection) -> String {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.server_name)
+        .unwrap_or(connection.remote_address().ip().to_string())
+}
+
+/// Closes a connection refused by its address alone, logging it in the audit
+/// log. The client can only read the close code once the handshake completes,
+/// so that is still awaited, in a task of its own.
+fn refuse(connecting: Connecting, code: VarInt, reason: &'static [u8], log: Option<Arc<AuditLog>>) {
+    tokio::spawn(async move {
+        let remote = connecting.remote_address();
+        let conn = match connecting.await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log_handshake_failure(remote, &e);
+                METRICS.handshake_failures.fetch_add(1, Relaxed);
+                return;
             }
+        };
+        audit::close(&conn, code, reason);
+        audit::finish(
+            log.as_deref(),
+            &conn,
+            &server_name(&conn),
+            None,
+            Instant::now(),
+        );
+    });
+}
+
+/// Tells supervisors that the server is accepting connections: prints a line
+/// for each listening address on stdout, and notifies systemd if started as a
+/// `Type=notify` service.
+fn announce_ready(endpoints: &[Endpoint], json: bool) {
+    for endpoint in endpoints {
+        let Ok(addr) = endpoint.local_addr() else {
+            continue;
+        };
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({"event": "listening", "addr": addr})
+            );
+        } else {
+            println!("listening on {}", addr);
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Err(e) = notify_systemd() {
+        warn!("[server] notifying systemd error: {}", e);
+    }
+}
+
+/// Sends `READY=1` to `$NOTIFY_SOCKET`, if set.
+#[cfg(target_os = "linux")]
+fn notify_systemd() -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr as UnixAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    // a leading @ names a socket in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => UnixAddr::from_abstract_name(name)?,
+        None => UnixAddr::from_pathname(&path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(b"READY=1", &addr)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn create_shutdown_signal() {
+    let mut stream = match ctrl_c() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("[server] create signal stream error: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    stream.recv().await;
+    info!("[server] got signal Ctrl-C");
+}
+#[cfg(not(windows))]
+async fn create_shutdown_signal() {
+    let (mut term, mut int) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(term), Ok(int)) => (term, int),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("[server] create signal stream error: {}", e);
+            return std::future::pending().await;
         }
     };
 
     tokio::select! {
-        _ = recv_thread => (),
-        _ = write_thread => (),
+        _ = term.recv() => info!("[server] got signal TERM"),
+        _ = int.recv() => info!("[server] got signal INT"),
+    }
+}
+
+/// Returns whether the error code reports a TLS alert, e.g. an untrusted
+/// certificate.
+fn is_tls_alert(code: impl Into<u64>) -> bool {
+    (0x100..0x200).contains(&code.into())
+}
+
+/// Logs why a connection failed before its handshake completed, by cause.
+fn log_handshake_failure(remote: SocketAddr, e: &ConnectionError) {
+    match e {
+        ConnectionError::VersionMismatch => error!(
+            remote:% = remote;
+            "[server] handshake with {} failed: no QUIC version in common", remote
+        ),
+        ConnectionError::TransportError(e) if is_tls_alert(e.code) => error!(
+            remote:% = remote;
+            "[server] handshake with {} failed: TLS error: {}", remote, e
+        ),
+        ConnectionError::TransportError(e) => error!(
+            remote:% = remote;
+            "[server] handshake with {} failed: transport error: {}", remote, e
+        ),
+        ConnectionError::ConnectionClosed(close) if is_tls_alert(close.error_code) => error!(
+            remote:% = remote;
+            "[server] handshake with {} failed: rejected by the client, e.g. for an untrusted certificate: {}",
+            remote,
+            close
+        ),
+        ConnectionError::ConnectionClosed(close) => error!(
+            remote:% = remote;
+            "[server] handshake with {} failed: aborted by the client: {}", remote, close
+        ),
+        ConnectionError::ApplicationClosed(close) => info!(
+            remote:% = remote;
+            "[server] handshake with {} failed: closed by the client: {}", remote, close
+        ),
+        ConnectionError::Reset => error!(
+            remote:% = remote;
+            "[server] handshake with {} failed: reset by the client", remote
+        ),
+        ConnectionError::TimedOut => error!(
+            remote:% = remote;
+            "[server] handshake with {} timed out", remote
+        ),
+        ConnectionError::LocallyClosed => info!(
+            remote:% = remote;
+            "[server] handshake with {} cancelled, shutting down", remote
+        ),
+    }
+}
+
+/// Takes a slot under --max-connections. When none is free, waits for one or
+/// closes the connection as busy, depending on --on-overload.
+async fn acquire_slot(
+    limit: Arc<Semaphore>,
+    connection: &quinn::Connection,
+    on_overload: Overload,
+) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = limit.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    match on_overload {
+        Overload::Queue => {
+            info!(
+                "[server] connection limit reached, queueing: {}",
+                connection.remote_address()
+            );
+            limit.acquire_owned().await.ok()
+        }
+        Overload::Reject => {
+            info!(
+                "[server] connection limit reached, rejecting: {}",
+                connection.remote_address()
+            );
+            audit::close(connection, close::SERVER_BUSY, b"server busy");
+            None
+        }
+    }
+}
+
+async fn handle_connection(
+    sni: &str,
+    proxy_for: &str,
+    connection: &quinn::Connection,
+    listen: SocketAddr,
+    options: Arc<Opt>,
+) {
+    METRICS.active_connections.fetch_add(1, Relaxed);
+    if let Some(secs) = options.trace_transport {
+        let interval = Duration::from_secs(secs);
+        tokio::spawn(stats::trace(connection.clone(), "server", interval));
+    }
+    let relayed = async {
+        if datagram::negotiated(connection, &options.alpn) {
+            proxy_datagrams(proxy_for, connection, listen, &options).await;
+        } else {
+            // the streams of a connection share its rate limit
+            let throttle = Throttle::new(options.rate_limit);
+            accept_streams(proxy_for, connection, listen, throttle, &options).await;
+        }
+    };
+    match options.max_duration {
+        Some(secs) => tokio::select! {
+            _ = relayed => (),
+            _ = tokio::time::sleep(Duration::from_secs(secs)) => {
+                info!(
+                    remote:% = connection.remote_address(), sni:% = sni;
+                    "[server] connection reached the max duration of {}s, closing it",
+                    secs
+                );
+                audit::close(connection, close::MAX_DURATION, b"max duration reached");
+            }
+        },
+        None => relayed.await,
     }
 
+    info!(
+        remote:% = connection.remote_address(), sni:% = sni;
+        "[server] connection ({}, {}) closed: {}",
+        connection.remote_address(),
+        sni,
+        stats::summary(connection)
+    );
+    METRICS.active_connections.fetch_sub(1, Relaxed);
     info!("[server] exit client");
+}
+
+/// Serves every bi-stream of the connection until it's closed.
+async fn accept_streams(
+    proxy_for: &str,
+    connection: &quinn::Connection,
+    listen: SocketAddr,
+    throttle: Throttle,
+    options: &Arc<Opt>,
+) {
+    // every bi-stream is a separate ssh session with its own backend connection
+    let streams = Arc::new(AtomicUsize::new(0));
+    loop {
+        let stream = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(ApplicationClosed(_)) | Err(LocallyClosed) => {
+                info!("[server] connection closed");
+                break;
+            }
+            Err(e) => {
+                error!("[server] accept quic stream error: {}", e);
+                break;
+            }
+        };
+        debug!("[server] quic stream accepted: {}", stream.0.id());
+        let proxy_for = proxy_for.to_owned();
+        let connection = connection.clone();
+        let options = options.clone();
+        let throttle = throttle.clone();
+        let streams = streams.clone();
+        streams.fetch_add(1, Relaxed);
+        tokio::spawn(async move {
+            handle_stream(
+                &proxy_for,
+                &connection,
+                listen,
+                stream,
+                throttle,
+                &options,
+                &streams,
+            )
+            .await;
+            streams.fetch_sub(1, Relaxed);
+        });
+    }
+}
+
+/// A connection to the ssh server, over TCP or a unix socket.
+trait Backend: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Backend for T {}
+
+/// Why connecting to the ssh server failed.
+enum DialError {
+    Resolve(String),
+    Connect(io::Error),
+    Timeout(u64),
+}
+
+impl DialError {
+    /// Returns the code and reason to close the connection with.
+    fn close_code(&self) -> (VarInt, &'static [u8]) {
+        match self {
+            DialError::Resolve(_) => (close::BACKEND_UNREACHABLE, b"backend resolution failed"),
+            DialError::Connect(_) => (close::BACKEND_UNREACHABLE, b"backend unreachable"),
+            DialError::Timeout(_) => (close::BACKEND_TIMEOUT, b"backend timeout"),
+        }
+    }
+}
+
+impl std::fmt::Display for DialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DialError::Resolve(e) => write!(f, "resolve error: {}", e),
+            DialError::Connect(e) => write!(f, "connect error: {}", e),
+            DialError::Timeout(secs) => write!(f, "timed out after {}s", secs),
+        }
+    }
+}
+
+/// Connects to the ssh server, retrying with exponential backoff as configured,
+/// and sends the PROXY protocol header if enabled. Returns the code and reason
+/// to close with on failure.
+async fn connect_backend(
+    proxy_for: &str,
+    connection: &quinn::Connection,
+    listen: SocketAddr,
+    options: &Opt,
+) -> Result<Box<dyn Backend>, (VarInt, &'static [u8])> {
+    let backend_timeout = Duration::from_secs(options.backend_timeout);
+    let mut delay = Duration::from_millis(options.backend_retry_delay);
+    let mut attempt = 0;
+    let mut backend = loop {
+        let dialed = timeout(backend_timeout, dial_backend(proxy_for, options))
+            .await
+            .unwrap_or(Err(DialError::Timeout(options.backend_timeout)));
+        let e = match dialed {
+            Ok(backend) => break backend,
+            Err(e) => e,
+        };
+        if attempt == options.backend_retries {
+            error!("[server] connect to ssh {} failed: {}", proxy_for, e);
+            METRICS.backend_connect_failures.fetch_add(1, Relaxed);
+            return Err(e.close_code());
+        }
+        attempt += 1;
+        warn!(
+            "[server] connect to ssh {} failed: {}, retrying in {}ms ({}/{})",
+            proxy_for,
+            e,
+            delay.as_millis(),
+            attempt,
+            options.backend_retries
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    };
+    if options.proxy_protocol {
+        let local_ip = connection.local_ip().unwrap_or(listen.ip());
+        let header = proxy_protocol::header(
+            connection.remote_address(),
+            SocketAddr::new(local_ip, listen.port()),
+        );
+        if let Err(e) = backend.write_all(&header).await {
+            error!("[server] send PROXY protocol header error: {}", e);
+            return Err((close::BACKEND_ERROR, b"backend error"));
+        }
+    }
+    Ok(backend)
+}
+
+/// Connects to the ssh server once.
+async fn dial_backend(proxy_for: &str, options: &Opt) -> Result<Box<dyn Backend>, DialError> {
+    if let Some(path) = proxy_for.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return match UnixStream::connect(path).await {
+            Ok(conn) => Ok(Box::new(conn)),
+            Err(e) => Err(DialError::Connect(e)),
+        };
+        #[cfg(not(unix))]
+        return Err(DialError::Connect(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unix socket is not supported on this platform: {}", path),
+        )));
+    }
+
+    // resolve on every connection so that the aim can follow DNS changes
+    let addrs: Vec<SocketAddr> = lookup_host(proxy_for)
+        .await
+        .map_err(|e| DialError::Resolve(e.to_string()))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(DialError::Resolve("no address found".into()));
+    }
+    debug!("[server] {} resolved to: {:?}", proxy_for, addrs);
 
-    // tokio::join!(recv_thread, write_thread);
+    match TcpStream::connect(&addrs[..]).await {
+        Ok(conn) => {
+            // a failure here only costs latency, the connection still works
+            if let Err(e) = configure_backend_socket(&conn, options) {
+                warn!("[server] set ssh connection socket options error: {}", e);
+            }
+            Ok(Box::new(conn))
+        }
+        Err(e) => Err(DialError::Connect(e)),
+    }
+}
+
+/// Applies --backend-nodelay and --backend-keepalive to a TCP connection to the
+/// ssh server.
+fn configure_backend_socket(conn: &TcpStream, options: &Opt) -> io::Result<()> {
+    if options.backend_nodelay {
+        conn.set_nodelay(true)?;
+    }
+    if let Some(secs) = options.backend_keepalive {
+        let interval = Duration::from_secs(secs);
+        let keepalive = socket2::TcpKeepalive::new().with_time(interval);
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+        let keepalive = keepalive.with_interval(interval);
+        socket2::SockRef::from(conn).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Connects to the ssh server and relays a bi-stream to it. `streams` counts
+/// the streams of the connection being served, this one included.
+async fn handle_stream(
+    proxy_for: &str,
+    connection: &quinn::Connection,
+    listen: SocketAddr,
+    stream: (SendStream, RecvStream),
+    throttle: Throttle,
+    options: &Opt,
+    streams: &AtomicUsize,
+) {
+    match connect_backend(proxy_for, connection, listen, options).await {
+        Ok(ssh_conn) => {
+            info!("[server] ssh connection established");
+            proxy_stream(ssh_conn, stream, connection, throttle, options).await;
+        }
+        // leave the other sessions of the connection alone
+        Err((code, _)) if streams.load(Relaxed) > 1 => {
+            let (mut send, mut recv) = stream;
+            let _ = send.reset(code);
+            let _ = recv.stop(code);
+        }
+        Err((code, reason)) => audit::close(connection, code, reason),
+    }
+}
+
+/// Connects to the ssh server and relays the datagrams of the connection to it.
+///
+/// The connection is closed once the ssh server closes its side, as datagrams
+/// can't carry an EOF.
+async fn proxy_datagrams(
+    proxy_for: &str,
+    connection: &quinn::Connection,
+    listen: SocketAddr,
+    options: &Opt,
+) {
+    let ssh_conn = match connect_backend(proxy_for, connection, listen, options).await {
+        Ok(ssh_conn) => ssh_conn,
+        Err((code, reason)) => return audit::close(connection, code, reason),
+    };
+    info!("[server] ssh connection established in datagram mode");
+    let ssh_conn = Tracked::new(Counted::new(ssh_conn, traffic::counters(connection)));
+    let stalled = ssh_conn.stalled(options.stream_timeout.map(Duration::from_secs));
+    let (ssh_recv, ssh_send) = tokio::io::split(ssh_conn);
+
+    tokio::select! {
+        sent = datagram::send_from(connection, ssh_recv, options.buffer_size) => match sent {
+            Ok(()) => {
+                info!("[server] ssh connection closed");
+                audit::close(connection, close::NORMAL, b"ssh connection closed");
+            }
+            Err(e) => {
+                error!("[server] relaying datagrams error: {}", e);
+                audit::close(connection, close::BACKEND_ERROR, b"relaying datagrams failed");
+            }
+        },
+        e = datagram::recv_into(connection, ssh_send) => {
+            if connection.close_reason().is_some() {
+                info!("[server] connection closed by client");
+            } else {
+                error!("[server] relaying datagrams error: {}", e);
+                audit::close(connection, close::BACKEND_ERROR, b"relaying datagrams failed");
+            }
+        }
+        _ = stalled => {
+            warn!("[server] no data relayed for {}s, closing the connection", options.stream_timeout.unwrap_or_default());
+            audit::close(connection, close::STREAM_TIMEOUT, b"stream timeout");
+        }
+    }
+}
+
+/// Relays the data between the ssh server and a bi-stream.
+///
+/// EOF on either side is propagated to the other as a half-close.
+/// On error or stream timeout the stream is reset, leaving the other streams of
+/// the connection alone.
+async fn proxy_stream<S>(
+    ssh_conn: S,
+    (quinn_send, mut quinn_recv): (SendStream, RecvStream),
+    connection: &quinn::Connection,
+    throttle: Throttle,
+    options: &Opt,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let id = quinn_send.id();
+    let mut quinn_send = Throttled::new(quinn_send, throttle);
+    let ssh_conn = Tracked::new(Counted::new(ssh_conn, traffic::counters(connection)));
+    let stalled = ssh_conn.stalled(options.stream_timeout.map(Duration::from_secs));
+
+    let copied = relay::copy_bidirectional(
+        ssh_conn,
+        &mut quinn_recv,
+        &mut quinn_send,
+        options.buffer_size,
+    );
+    let relayed = tokio::select! {
+        relayed = copied => Some(relayed),
+        _ = stalled => None,
+    };
+    let Some(relayed) = relayed else {
+        warn!(
+            remote:% = connection.remote_address();
+            "[server] no data relayed for {}s, closing the stream",
+            options.stream_timeout.unwrap_or_default()
+        );
+        let _ = quinn_send.get_mut().reset(close::STREAM_TIMEOUT);
+        let _ = quinn_recv.stop(close::STREAM_TIMEOUT);
+        debug!("[server] quic stream closed: {}", id);
+        return;
+    };
+
+    match relayed {
+        Ok((to_client, to_ssh)) => {
+            info!(
+                remote:% = connection.remote_address();
+                "[server] ssh connection closed: {} bytes to ssh server, {} bytes to client",
+                to_ssh, to_client
+            );
+        }
+        // the client closes the connection once it has received everything
+        Err(_) if connection.close_reason().is_some() => {
+            info!("[server] connection closed by client");
+        }
+        // the client drops what it was sending when its stdout goes away
+        Err(e) if is_normal_reset(&e) => {
+            info!("[server] stream reset by client");
+        }
+        Err(e) => {
+            error!("[server] relaying data error: {}", e);
+            let _ = quinn_send.get_mut().reset(close::BACKEND_ERROR);
+            let _ = quinn_recv.stop(close::BACKEND_ERROR);
+        }
+    }
+
+    debug!("[server] quic stream closed: {}", id);
+}
+
+/// Returns the common name of the client certificate if the client sent one,
+/// `Some(None)` when it has none that can be read.
+fn client_common_name(connection: &quinn::Connection) -> Option<Option<String>> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+    Some(
+        certs
+            .first()
+            .and_then(|cert| crate::x509::common_name(&cert.0)),
+    )
+}
+
+/// Whether a relay error is the client resetting the stream with the normal code.
+fn is_normal_reset(e: &std::io::Error) -> bool {
+    let reset = e.get_ref().and_then(|e| e.downcast_ref::<ReadError>());
+    matches!(reset, Some(ReadError::Reset(code)) if *code == close::NORMAL)
+}
+
+/// Builder of a server proxying QUIC connections to ssh servers, for use in
+/// another program. It starts from the defaults of the `server` command.
+pub struct Server {
+    options: Opt,
+    route: Option<Router>,
+}
+
+impl Server {
+    /// Starts building a server listening on the address.
+    pub fn new(listen: SocketAddr) -> Self {
+        let mut options = Opt::try_parse_from(["server"]).expect("the defaults are valid");
+        options.listen = vec![listen];
+        Server {
+            options,
+            route: None,
+        }
+    }
+
+    /// Uses the certificate chain and private key in PEM files instead of a
+    /// self-signed certificate, like --cert and --key.
+    pub fn cert(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.options.cert_path = Some(cert_path.into());
+        self.options.key_path = Some(key_path.into());
+        self
+    }
+
+    /// Accepts only clients with a certificate signed by one of the CA
+    /// certificates in a PEM file, like --client-ca.
+    pub fn client_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.client_ca_path = Some(path.into());
+        self
+    }
+
+    /// Uses this ALPN protocol identifier, like --alpn.
+    pub fn alpn(mut self, alpn: impl Into<String>) -> Self {
+        self.options.alpn = alpn.into();
+        self
+    }
+
+    /// Picks the ssh server for each connection by its SNI, as `host:port` or
+    /// `unix:path`. Without a router every connection goes to 127.0.0.1:22.
+    pub fn route(mut self, route: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        self.route = Some(Arc::new(route));
+        self
+    }
+
+    /// Serves connections until `shutdown` completes, then gives the active ones
+    /// the shutdown grace period to finish before closing them.
+    pub async fn serve(self, shutdown: impl Future<Output = ()>) -> Result<(), Box<dyn Error>> {
+        let endpoints = make_server_endpoints(&self.options)?;
+        let route = self.route.unwrap_or_else(|| {
+            let aim = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22).to_string();
+            Arc::new(move |_: &str| aim.clone())
+        });
+        serve(
+            endpoints,
+            Arc::new(self.options),
+            Routing::Router(route),
+            None,
+            shutdown,
+        )
+        .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(toml: &str) -> ServerConf {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn aim(entry: Option<ProxyEntry>) -> Option<String> {
+        entry.map(|entry| entry.aim().clone())
+    }
+
+    #[test]
+    fn exact_entry_wins_over_wildcard() {
+        let conf = conf(
+            r#"
+            [proxy]
+            "a.example.com" = "127.0.0.1:2201"
+            "*.example.com" = "127.0.0.1:2202"
+            "#,
+        );
+        assert_eq!(
+            aim(conf.matched_entry("a.example.com")).as_deref(),
+            Some("127.0.0.1:2201")
+        );
+        assert_eq!(
+            aim(conf.matched_entry("b.example.com")).as_deref(),
+            Some("127.0.0.1:2202")
+        );
+    }
+
+    #[test]
+    fn longest_wildcard_suffix_wins() {
+        let conf = conf(
+            r#"
+            [proxy]
+            "*.example.com" = "127.0.0.1:2201"
+            "*.dev.example.com" = "127.0.0.1:2202"
+            "#,
+        );
+        assert_eq!(
+            aim(conf.matched_entry("host.dev.example.com")).as_deref(),
+            Some("127.0.0.1:2202")
+        );
+        assert_eq!(
+            aim(conf.matched_entry("host.prod.example.com")).as_deref(),
+            Some("127.0.0.1:2201")
+        );
+    }
+
+    #[test]
+    fn wildcard_does_not_match_the_bare_domain() {
+        let conf = conf(
+            r#"
+            [proxy]
+            "*.example.com" = "127.0.0.1:2201"
+            "#,
+        );
+        assert!(conf.matched_entry("example.com").is_none());
+        assert!(conf.matched_entry("badexample.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn unmatched_sni_falls_back_to_default() {
+        let conf = conf(
+            r#"
+            [proxy]
+            "*.example.com" = "127.0.0.1:2201"
+            default = "127.0.0.1:2202"
+            "#,
+        );
+        assert!(conf.matched_entry("example.org").is_none());
+        let routing = Routing::Conf(Arc::new(RwLock::new(conf)), "127.0.0.1:22".into());
+        assert_eq!(routing.route("example.org", None).await.0, "127.0.0.1:2202");
+        let routing = Routing::Conf(
+            Arc::new(RwLock::new(ServerConf::new())),
+            "127.0.0.1:22".into(),
+        );
+        assert_eq!(routing.route("example.org", None).await.0, "127.0.0.1:22");
+    }
+
+    #[tokio::test]
+    async fn certificate_without_entry_is_not_routed_by_sni() {
+        let conf = conf(
+            r#"
+            [proxy]
+            "a.example.com" = "127.0.0.1:2201"
+            default = "127.0.0.1:2202"
+            [proxy-by-cn]
+            alice = "127.0.0.1:2203"
+            "#,
+        );
+        let routing = Routing::Conf(Arc::new(RwLock::new(conf)), "127.0.0.1:22".into());
+        let route = |cert| routing.route("a.example.com", cert);
+        assert_eq!(route(Some(Some("alice"))).await.0, "127.0.0.1:2203");
+        assert_eq!(route(Some(Some("bob"))).await.0, "127.0.0.1:2202");
+        assert_eq!(route(Some(None)).await.0, "127.0.0.1:2202");
+        assert_eq!(route(None).await.0, "127.0.0.1:2201");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolver_answers_the_snis_without_entry() {
+        let calls =
+            std::env::temp_dir().join(format!("quicssh-test-{}-resolver", std::process::id()));
+        let script = format!(
+            r#"read sni; echo "$sni" >> {}; case "$sni" in slow.test) exec sleep 5;; *) echo 127.0.0.1:2203;; esac"#,
+            calls.display()
+        );
+        let conf: ServerConf = toml::from_str(&format!(
+            r#"
+            [proxy]
+            "a.example.com" = "127.0.0.1:2201"
+            default = "127.0.0.1:2202"
+            [resolver]
+            command = ["sh", "-c", '{}']
+            timeout = 500
+            "#,
+            script
+        ))
+        .unwrap();
+        let routing = Routing::Conf(Arc::new(RwLock::new(conf)), "127.0.0.1:22".into());
+        let route = |sni: &'static str| routing.route(sni, None);
+
+        assert_eq!(route("a.example.com").await.0, "127.0.0.1:2201");
+        assert_eq!(route("b.test").await.0, "127.0.0.1:2203");
+        // the second answer comes from the cache
+        assert_eq!(route("b.test").await.0, "127.0.0.1:2203");
+        assert_eq!(route("slow.test").await.0, "127.0.0.1:2202");
+
+        let called = std::fs::read_to_string(&calls).unwrap_or_default();
+        let _ = std::fs::remove_file(&calls);
+        assert_eq!(called, "b.test\nslow.test\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unsets_the_activation_variables() {
+        // meant for another process, so none are taken
+        std::env::set_var("LISTEN_PID", "1");
+        std::env::set_var("LISTEN_FDS", "1");
+        std::env::set_var("LISTEN_FDNAMES", "quic");
+        assert!(activated_sockets().unwrap().is_none());
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            assert!(std::env::var_os(name).is_none(), "{} is still set", name);
+        }
+    }
 }
@@ -0,0 +1,67 @@
+//! Keeping the server name out of the clear, for the client's `--sni-mode`.
+//!
+//! The SNI of the TLS handshake is not encrypted, so anyone on the path learns
+//! which host the client tunnels to. rustls doesn't support Encrypted Client
+//! Hello yet; instead the client can send no SNI at all, leaving the server to
+//! route by the client address or its `default` entry, or send a decoy and the
+//! real name in a route header: a unidirectional stream the client opens once
+//! the connection is encrypted. Client and server agree on the route header
+//! with the ALPN token returned by [`alpn`].
+
+use clap::ValueEnum;
+use quinn::{crypto, Connection};
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+
+/// Time the server waits for the route header.
+pub const ROUTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest route header accepted, the longest DNS name.
+const MAX_ROUTE_LEN: usize = 253;
+
+/// What the client sends as SNI.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SniMode {
+    /// The name of the server, as usual.
+    #[default]
+    Real,
+    /// No SNI, the server routes as for a client connecting by address.
+    None,
+    /// The decoy, with the name of the server in the route header.
+    Fixed,
+}
+
+/// Returns the ALPN token selecting the route header, derived from the one of
+/// the stream mode.
+pub fn alpn(base: &str) -> Vec<u8> {
+    format!("{}-route", base).into_bytes()
+}
+
+/// Returns whether the route header was negotiated on the connection.
+pub fn negotiated(connection: &Connection, base: &str) -> bool {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .is_some_and(|protocol| protocol == alpn(base))
+}
+
+/// Sends the name of the server in the route header.
+pub async fn send_route(connection: &Connection, name: &str) -> Result<(), Box<dyn Error>> {
+    let mut send = connection.open_uni().await?;
+    send.write_all(name.as_bytes()).await?;
+    // finishing waits for the ack, don't hold the session up for it
+    tokio::spawn(async move { send.finish().await });
+    Ok(())
+}
+
+/// Reads the name of the server from the route header.
+pub async fn recv_route(connection: &Connection) -> Result<String, Box<dyn Error>> {
+    let mut recv = connection.accept_uni().await?;
+    let name = recv.read_to_end(MAX_ROUTE_LEN).await?;
+    let name = String::from_utf8(name)?;
+    rustls::ServerName::try_from(name.as_str())?;
+    Ok(name)
+}
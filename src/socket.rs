@@ -0,0 +1,78 @@
+//! A UDP socket for quinn that sends and receives plain datagrams.
+//!
+//! quinn-udp marks every packet with its ECN bits through an `IP_TOS` or
+//! `IPV6_TCLASS` control message, which overrides the traffic class set on the
+//! socket, and batches packets with GSO and GRO. This socket does neither, so the
+//! socket options apply as set, at the cost of some throughput. It's also the
+//! way around kernels that mishandle segmentation offload, for `--no-gso`.
+
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::AsyncUdpSocket;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::task::{ready, Context, Poll};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+
+#[derive(Debug)]
+pub struct PlainUdpSocket(UdpSocket);
+
+impl PlainUdpSocket {
+    pub fn new(socket: std::net::UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self(UdpSocket::from_std(socket)?))
+    }
+}
+
+impl AsyncUdpSocket for PlainUdpSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        for (i, transmit) in transmits.iter().enumerate() {
+            // quinn may still batch datagrams for GSO, send them one by one
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for (j, segment) in transmit.contents.chunks(segment_size).enumerate() {
+                match self.0.poll_send_to(cx, segment, transmit.destination) {
+                    Poll::Ready(Ok(_)) => (),
+                    // nothing of this transmit went out, report the ones before it
+                    result if j == 0 => {
+                        return match (i, result) {
+                            (0, Poll::Ready(Err(e))) => Poll::Ready(Err(e)),
+                            (0, _) => Poll::Pending,
+                            _ => Poll::Ready(Ok(i)),
+                        }
+                    }
+                    // QUIC recovers the rest of a partly sent transmit as lost packets
+                    _ => return Poll::Ready(Ok(i + 1)),
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(&mut bufs[0]);
+        let addr = ready!(self.0.poll_recv_from(cx, &mut buf))?;
+        let len = buf.filled().len();
+        meta[0] = RecvMeta {
+            addr,
+            len,
+            stride: len,
+            ecn: None,
+            dst_ip: None,
+        };
+        Poll::Ready(Ok(1))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
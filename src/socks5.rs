@@ -0,0 +1,217 @@
+//! Sending the QUIC packets through the UDP relay of a SOCKS5 proxy, with the
+//! UDP ASSOCIATE command of RFC 1928.
+//!
+//! Only proxies which accept clients without authentication are supported.
+//! Fragmented datagrams and relayed datagrams from a domain name are dropped.
+
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::AsyncUdpSocket;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpStream, UdpSocket};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const UDP_ASSOCIATE: u8 = 3;
+const SUCCEEDED: u8 = 0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// A UDP association with the proxy, which lasts as long as its TCP connection.
+#[derive(Debug)]
+pub struct Association {
+    // the proxy ends the association when this closes
+    _control: TcpStream,
+    relay: SocketAddr,
+}
+
+impl Association {
+    /// The address of the relay to send the datagrams to.
+    pub fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Asks the proxy for a UDP relay.
+pub async fn associate(proxy: &str) -> io::Result<Association> {
+    let mut control = TcpStream::connect(proxy).await?;
+
+    control.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut reply = [0; 2];
+    control.read_exact(&mut reply).await?;
+    if reply != [VERSION, NO_AUTHENTICATION] {
+        return Err(protocol_error(
+            "the proxy requires authentication, which is unsupported".into(),
+        ));
+    }
+
+    // the address the datagrams will come from isn't known yet, which zeros say
+    control
+        .write_all(&[VERSION, UDP_ASSOCIATE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    let mut reply = [0; 4];
+    control.read_exact(&mut reply).await?;
+    if reply[0] != VERSION || reply[1] != SUCCEEDED {
+        return Err(protocol_error(format!(
+            "the proxy refused UDP ASSOCIATE with reply {}",
+            reply[1]
+        )));
+    }
+    let ip: IpAddr = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            control.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).into()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            control.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).into()
+        }
+        atyp => {
+            return Err(protocol_error(format!(
+                "unsupported relay address type {}",
+                atyp
+            )))
+        }
+    };
+    let port = control.read_u16().await?;
+    // an unspecified address means the relay is on the proxy itself
+    let ip = match ip.is_unspecified() {
+        true => control.peer_addr()?.ip(),
+        false => ip,
+    };
+    Ok(Association {
+        _control: control,
+        relay: SocketAddr::new(ip, port),
+    })
+}
+
+/// Appends the SOCKS5 UDP request header for the destination to `buf`.
+fn write_header(buf: &mut Vec<u8>, destination: SocketAddr) {
+    // reserved and fragment number
+    buf.extend_from_slice(&[0, 0, 0]);
+    match destination.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&destination.port().to_be_bytes());
+}
+
+/// Parses the SOCKS5 UDP header of a relayed datagram, returning its source and
+/// the length of the header, or `None` if the datagram is to be dropped.
+fn read_header(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, len): (IpAddr, usize) = match datagram.get(..4)? {
+        [0, 0, 0, ATYP_IPV4] => {
+            let octets: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+            (octets.into(), 8)
+        }
+        [0, 0, 0, ATYP_IPV6] => {
+            let octets: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+            (octets.into(), 20)
+        }
+        // fragments, domain names and garbage
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(datagram.get(len..len + 2)?.try_into().ok()?);
+    Some((SocketAddr::new(ip, port), len + 2))
+}
+
+/// A socket for quinn that sends every packet through the relay of a SOCKS5
+/// proxy. Like `PlainUdpSocket`, it doesn't batch packets nor set ECN bits.
+#[derive(Debug)]
+pub struct Socks5UdpSocket {
+    socket: UdpSocket,
+    association: Association,
+}
+
+impl Socks5UdpSocket {
+    pub fn new(socket: std::net::UdpSocket, association: Association) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: UdpSocket::from_std(socket)?,
+            association,
+        })
+    }
+}
+
+impl AsyncUdpSocket for Socks5UdpSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut datagram = Vec::new();
+        for (i, transmit) in transmits.iter().enumerate() {
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for (j, segment) in transmit.contents.chunks(segment_size).enumerate() {
+                datagram.clear();
+                write_header(&mut datagram, transmit.destination);
+                datagram.extend_from_slice(segment);
+                match self
+                    .socket
+                    .poll_send_to(cx, &datagram, self.association.relay)
+                {
+                    Poll::Ready(Ok(_)) => (),
+                    // nothing of this transmit went out, report the ones before it
+                    result if j == 0 => {
+                        return match (i, result) {
+                            (0, Poll::Ready(Err(e))) => Poll::Ready(Err(e)),
+                            (0, _) => Poll::Pending,
+                            _ => Poll::Ready(Ok(i)),
+                        }
+                    }
+                    // QUIC recovers the rest of a partly sent transmit as lost packets
+                    _ => return Poll::Ready(Ok(i + 1)),
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut buf = ReadBuf::new(&mut bufs[0]);
+            let from = ready!(self.socket.poll_recv_from(cx, &mut buf))?;
+            let len = buf.filled().len();
+            if from != self.association.relay {
+                continue;
+            }
+            let Some((source, header_len)) = read_header(&bufs[0][..len]) else {
+                continue;
+            };
+            bufs[0].copy_within(header_len..len, 0);
+            let len = len - header_len;
+            meta[0] = RecvMeta {
+                addr: source,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
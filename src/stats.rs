@@ -0,0 +1,82 @@
+use log::debug;
+use quinn::{crypto, Connection};
+use std::time::Duration;
+
+/// Formats the statistics of a connection for the log line on close.
+pub fn summary(connection: &Connection) -> String {
+    let stats = connection.stats();
+    format!(
+        "rtt {}ms, {} bytes sent and {} received over UDP, {} packets lost, cwnd {} bytes",
+        stats.path.rtt.as_millis(),
+        stats.udp_tx.bytes,
+        stats.udp_rx.bytes,
+        stats.path.lost_packets,
+        stats.path.cwnd
+    )
+}
+
+/// Returns the ALPN protocol negotiated in the handshake, for the log line on connect.
+///
+/// quinn doesn't expose the TLS cipher suite nor the QUIC version in use, so
+/// the protocol is all there is to log about the handshake.
+pub fn alpn(connection: &Connection) -> String {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .map_or("none".into(), |protocol| {
+            String::from_utf8_lossy(&protocol).into_owned()
+        })
+}
+
+/// Logs the path statistics of the connection every `interval` at the debug
+/// level, for `--trace-transport`, until the connection closes. A tick where
+/// nothing changed is skipped.
+///
+/// quinn doesn't expose the current MTU nor the bytes in flight: the MTU
+/// discovery shows in the probe counts, and in the datagram size in the
+/// datagram mode.
+pub async fn trace(connection: Connection, side: &'static str, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    let mut last = connection.stats();
+    loop {
+        tokio::select! {
+            _ = connection.closed() => return,
+            _ = ticks.tick() => (),
+        }
+        let now = connection.stats();
+        let (before, path) = (&last.path, &now.path);
+        if before.rtt == path.rtt
+            && before.cwnd == path.cwnd
+            && before.sent_packets == path.sent_packets
+            && last.udp_rx.datagrams == now.udp_rx.datagrams
+        {
+            continue;
+        }
+        let mut line = format!(
+            "rtt {}ms, cwnd {} bytes ({:+}), {} packets sent (+{}), {} lost (+{}), {} congestion events (+{}), {} bytes sent (+{}) and {} received (+{}) over UDP, {} MTU probes sent, {} lost, {} black holes",
+            path.rtt.as_millis(),
+            path.cwnd,
+            path.cwnd as i64 - before.cwnd as i64,
+            path.sent_packets,
+            path.sent_packets - before.sent_packets,
+            path.lost_packets,
+            path.lost_packets - before.lost_packets,
+            path.congestion_events,
+            path.congestion_events - before.congestion_events,
+            now.udp_tx.bytes,
+            now.udp_tx.bytes - last.udp_tx.bytes,
+            now.udp_rx.bytes,
+            now.udp_rx.bytes - last.udp_rx.bytes,
+            path.sent_plpmtud_probes,
+            path.lost_plpmtud_probes,
+            path.black_holes_detected
+        );
+        if let Some(size) = connection.max_datagram_size() {
+            line += &format!(", max datagram {} bytes", size);
+        }
+        debug!(remote:% = connection.remote_address(); "[{}] transport: {}", side, line);
+        last = now;
+    }
+}
@@ -0,0 +1,150 @@
+//! Pacing of the data written into QUIC streams, for `--rate-limit`.
+//!
+//! This sits above QUIC's flow and congestion control, which still apply: it only
+//! caps the average rate, with bursts of up to a tenth of a second of it.
+
+use bytes::Bytes;
+use quinn::{SendStream, WriteError};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::{sleep, Instant, Sleep};
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    // goes negative when several writers take tokens at once, delaying the next
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.last_refill = now;
+    }
+}
+
+/// A token bucket of bytes, shared by the clones of it. Unlimited if created
+/// without a rate.
+#[derive(Clone)]
+pub struct Throttle(Option<Arc<Mutex<Bucket>>>);
+
+impl Throttle {
+    /// Allows `rate` bytes per second, or any rate if it's `None`.
+    pub fn new(rate: Option<u64>) -> Self {
+        Throttle(rate.map(|rate| {
+            let rate = rate as f64;
+            let burst = (rate / 10.0).max(1.0);
+            Arc::new(Mutex::new(Bucket {
+                rate,
+                burst,
+                tokens: burst,
+                last_refill: Instant::now(),
+            }))
+        }))
+    }
+
+    /// Returns how many of `wanted` bytes may be written now, or how long to wait
+    /// for them.
+    fn available(&self, wanted: usize) -> Result<usize, Duration> {
+        let Some(bucket) = &self.0 else {
+            return Ok(wanted);
+        };
+        let mut bucket = bucket.lock().unwrap();
+        bucket.refill();
+        // larger writes are split, as the bucket never holds enough for them
+        let wanted = (wanted as f64).min(bucket.burst);
+        if bucket.tokens >= wanted {
+            return Ok(wanted as usize);
+        }
+        Err(Duration::from_secs_f64(
+            (wanted - bucket.tokens) / bucket.rate,
+        ))
+    }
+
+    fn take(&self, written: usize) {
+        if let Some(bucket) = &self.0 {
+            bucket.lock().unwrap().tokens -= written as f64;
+        }
+    }
+}
+
+/// Wraps the sending end of a relay, pacing the writes with a [`Throttle`].
+pub struct Throttled<W> {
+    inner: W,
+    throttle: Throttle,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> Throttled<W> {
+    pub fn new(inner: W, throttle: Throttle) -> Self {
+        Throttled {
+            inner,
+            throttle,
+            delay: None,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl Throttled<SendStream> {
+    /// Hands the chunk to quinn without copying it, pacing it like the writes.
+    pub async fn write_chunk(&mut self, mut chunk: Bytes) -> Result<(), WriteError> {
+        while !chunk.is_empty() {
+            let len = loop {
+                match self.throttle.available(chunk.len()) {
+                    Ok(len) => break len,
+                    Err(wait) => sleep(wait).await,
+                }
+            };
+            self.inner.write_chunk(chunk.split_to(len)).await?;
+            self.throttle.take(len);
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+        let len = loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            match self.throttle.available(buf.len()) {
+                Ok(len) => break len,
+                Err(wait) => self.delay = Some(Box::pin(sleep(wait))),
+            }
+        };
+        let polled = Pin::new(&mut self.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(written)) = polled {
+            self.throttle.take(written);
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,170 @@
+//! Live byte counts of the active connections, which the server logs on SIGUSR1
+//! to look into a stuck transfer without setting up metrics.
+
+use std::collections::BTreeMap;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::metrics::METRICS;
+
+/// Bytes relayed so far over the streams of a connection.
+#[derive(Default)]
+pub struct Counters {
+    to_backend: AtomicU64,
+    to_client: AtomicU64,
+}
+
+impl Counters {
+    /// Returns the bytes relayed to the ssh server and to the client.
+    pub fn totals(&self) -> (u64, u64) {
+        (self.to_backend.load(Relaxed), self.to_client.load(Relaxed))
+    }
+}
+
+struct Entry {
+    remote: SocketAddr,
+    sni: String,
+    backend: String,
+    since: Instant,
+    counters: Arc<Counters>,
+}
+
+/// The active connections by stable id.
+static ACTIVE: Mutex<BTreeMap<usize, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Keeps a connection in the snapshots until dropped.
+pub struct Registration(usize, Arc<Counters>);
+
+impl Registration {
+    /// The byte counts of the connection, which outlive the registration.
+    pub fn counters(&self) -> &Arc<Counters> {
+        &self.1
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn register(connection: &quinn::Connection, sni: &str, backend: &str) -> Registration {
+    let id = connection.stable_id();
+    let counters = Arc::<Counters>::default();
+    ACTIVE.lock().unwrap().insert(
+        id,
+        Entry {
+            remote: connection.remote_address(),
+            sni: sni.to_string(),
+            backend: backend.to_string(),
+            since: Instant::now(),
+            counters: counters.clone(),
+        },
+    );
+    Registration(id, counters)
+}
+
+/// Returns the counters of a registered connection, or detached ones.
+pub fn counters(connection: &quinn::Connection) -> Arc<Counters> {
+    ACTIVE
+        .lock()
+        .unwrap()
+        .get(&connection.stable_id())
+        .map(|entry| entry.counters.clone())
+        .unwrap_or_default()
+}
+
+/// A line per active connection, with its byte counts so far.
+pub fn snapshot() -> Vec<String> {
+    ACTIVE
+        .lock()
+        .unwrap()
+        .values()
+        .map(|entry| {
+            format!(
+                "({}, {}) -> {}: {} bytes to ssh server, {} bytes to client in {:.0}s",
+                entry.remote,
+                entry.sni,
+                entry.backend,
+                entry.counters.to_backend.load(Relaxed),
+                entry.counters.to_client.load(Relaxed),
+                entry.since.elapsed().as_secs_f64()
+            )
+        })
+        .collect()
+}
+
+/// Wraps the ssh server end of a relay, counting what is written to it as bytes
+/// to the ssh server and what is read from it as bytes to the client, in the
+/// connection's counters and the metrics as the data goes through.
+pub struct Counted<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, counters: Arc<Counters>) -> Self {
+        Counted { inner, counters }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - filled) as u64;
+        self.counters.to_client.fetch_add(read, Relaxed);
+        METRICS.bytes_to_client.fetch_add(read, Relaxed);
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.counters.to_backend.fetch_add(n as u64, Relaxed);
+            METRICS.bytes_to_backend.fetch_add(n as u64, Relaxed);
+        }
+        polled
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.counters.to_backend.fetch_add(n as u64, Relaxed);
+            METRICS.bytes_to_backend.fetch_add(n as u64, Relaxed);
+        }
+        polled
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,173 @@
+//! Just enough DER to read the common name in the subject of a certificate, for
+//! routing by the client certificate with the `proxy-by-cn` table of the conf file.
+//!
+//! rustls has already verified the certificate by then, so a certificate this
+//! can't make sense of only goes without a common name.
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+/// The explicit version tag at the start of a v2 or v3 certificate.
+const VERSION: u8 = 0xa0;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const IA5_STRING: u8 = 0x16;
+/// id-at-commonName, 2.5.4.3
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Splits the element at the start of `der` into its tag, its content and what
+/// follows it.
+fn split(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let octets = usize::from(first & 0x7f);
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let (octets, rest) = rest.split_at(octets);
+        let len = octets
+            .iter()
+            .fold(0, |len, &octet| len << 8 | usize::from(octet));
+        (len, rest)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (content, rest) = rest.split_at(len);
+    Some((tag, content, rest))
+}
+
+/// Splits the element at the start of `der` if it has the tag.
+fn expect(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match split(der)? {
+        (found, content, rest) if found == tag => Some((content, rest)),
+        _ => None,
+    }
+}
+
+/// Returns the first common name in the subject of the DER certificate.
+pub fn common_name(cert: &[u8]) -> Option<String> {
+    let (cert, _) = expect(cert, SEQUENCE)?;
+    let (tbs, _) = expect(cert, SEQUENCE)?;
+    let mut fields = tbs;
+    if let Some((_, rest)) = expect(fields, VERSION) {
+        fields = rest;
+    }
+    // the serial number, signature algorithm, issuer and validity come first
+    for _ in 0..4 {
+        fields = split(fields)?.2;
+    }
+    let (mut names, _) = expect(fields, SEQUENCE)?;
+    while !names.is_empty() {
+        let (mut attributes, rest) = expect(names, SET)?;
+        names = rest;
+        while !attributes.is_empty() {
+            let (attribute, rest) = expect(attributes, SEQUENCE)?;
+            attributes = rest;
+            let (oid, value) = expect(attribute, OBJECT_IDENTIFIER)?;
+            if oid != COMMON_NAME {
+                continue;
+            }
+            return match split(value)? {
+                (UTF8_STRING | PRINTABLE_STRING | IA5_STRING, name, _) => {
+                    String::from_utf8(name.to_vec()).ok()
+                }
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes an element, in the long form when the content needs it.
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = content.len();
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            let octets: Vec<u8> = len
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|&octet| octet == 0)
+                .collect();
+            out.push(0x80 | octets.len() as u8);
+            out.extend(octets);
+        }
+        out.extend(content);
+        out
+    }
+
+    fn attribute(oid: &[u8], tag: u8, value: &[u8]) -> Vec<u8> {
+        der(
+            SET,
+            &der(
+                SEQUENCE,
+                &[der(OBJECT_IDENTIFIER, oid), der(tag, value)].concat(),
+            ),
+        )
+    }
+
+    /// A v1 certificate, without the version, with the subject attributes.
+    fn cert(subject: &[Vec<u8>]) -> Vec<u8> {
+        let tbs = [
+            der(0x02, &[1]),
+            der(SEQUENCE, &der(OBJECT_IDENTIFIER, &[0x2a, 0x03])),
+            der(SEQUENCE, &[]),
+            der(SEQUENCE, &[]),
+            der(SEQUENCE, &subject.concat()),
+        ]
+        .concat();
+        der(SEQUENCE, &der(SEQUENCE, &tbs))
+    }
+
+    const ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0a];
+
+    #[test]
+    fn reads_a_generated_certificate() {
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "alice");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        // well over 127 bytes, so the outer lengths are in the long form
+        let der = cert.serialize_der().unwrap();
+        assert_eq!(common_name(&der).as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn reads_a_v1_certificate_with_long_form_lengths() {
+        let name = "a".repeat(300);
+        let der = cert(&[
+            attribute(ORGANIZATION, UTF8_STRING, b"example"),
+            attribute(COMMON_NAME, PRINTABLE_STRING, name.as_bytes()),
+        ]);
+        assert_eq!(common_name(&der), Some(name));
+    }
+
+    #[test]
+    fn no_common_name() {
+        let der = cert(&[attribute(ORGANIZATION, UTF8_STRING, b"example")]);
+        assert_eq!(common_name(&der), None);
+    }
+
+    #[test]
+    fn unsupported_string_type() {
+        // BMPString, UTF-16
+        let der = cert(&[attribute(COMMON_NAME, 0x1e, &[0, b'a', 0, b'b'])]);
+        assert_eq!(common_name(&der), None);
+    }
+
+    #[test]
+    fn truncated_certificate() {
+        let der = cert(&[attribute(COMMON_NAME, UTF8_STRING, b"alice")]);
+        assert_eq!(common_name(&der[..der.len() - 1]), None);
+    }
+}
@@ -0,0 +1,573 @@
+//! Runs the client and the server binaries against a TCP echo backend.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BIN: &str = env!("CARGO_BIN_EXE_quicssh-rs");
+
+/// A config dir without a quicssh conf file, so that one on the machine
+/// running the tests doesn't change their routes.
+fn empty_config_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("quicssh-test-{}-empty", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Kills the server when the test ends, passing or not.
+struct Server(Child);
+
+impl Server {
+    /// Starts the server on a free port, returning once it's listening.
+    fn start(proxy_to: &str) -> (Self, SocketAddr) {
+        Self::start_with(proxy_to, &[])
+    }
+
+    /// Starts the server like [`Server::start`], with extra arguments.
+    fn start_with(proxy_to: &str, args: &[&str]) -> (Self, SocketAddr) {
+        let mut child = Command::new(BIN)
+            .args(["server", "--listen", "127.0.0.1:0"])
+            .args(["--proxy-to", proxy_to])
+            .args(args)
+            .env("XDG_CONFIG_HOME", empty_config_dir())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut ready = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut ready)
+            .unwrap();
+        let listen = ready
+            .trim_end()
+            .strip_prefix("listening on ")
+            .unwrap_or_else(|| panic!("unexpected ready line: {:?}", ready))
+            .parse()
+            .unwrap();
+        (Server(child), listen)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Echoes everything back on each connection until the peer finishes its side.
+fn start_echo_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buf = [0; 16384];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => stream.write_all(&buf[..n]).unwrap(),
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Answers the first read on each connection with `response`, then closes it.
+fn start_fixed_backend(response: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            stream.write_all(response).unwrap();
+        }
+    });
+    addr
+}
+
+/// Reads each connection to EOF, then answers with the number of bytes read
+/// and closes it.
+fn start_draining_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut request = Vec::new();
+                stream.read_to_end(&mut request).unwrap();
+                write!(stream, "read {} bytes", request.len()).unwrap();
+            });
+        }
+    });
+    addr
+}
+
+/// Runs the client with `input` as stdin, returning its exit code and stdout.
+fn run_client(server: SocketAddr, input: Vec<u8>) -> (Option<i32>, Vec<u8>) {
+    run_client_with(server, &[], input)
+}
+
+/// Runs the client like [`run_client`], with extra arguments.
+fn run_client_with(server: SocketAddr, args: &[&str], input: Vec<u8>) -> (Option<i32>, Vec<u8>) {
+    let mut child = Command::new(BIN)
+        .arg("client")
+        .args(args)
+        .arg(format!("quic://{}", server))
+        .env("XDG_CONFIG_HOME", empty_config_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    // write from another thread so that a full stdout pipe can't deadlock
+    let writer = thread::spawn(move || {
+        let _ = stdin.write_all(&input);
+    });
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    (output.status.code(), output.stdout)
+}
+
+#[test]
+fn forwards_stdin_eof_to_the_backend() {
+    let backend = start_draining_backend();
+    let (_server, listen) = Server::start(&backend.to_string());
+
+    // the backend only answers once the client's EOF reaches it as a half-close
+    let (code, output) = run_client(listen, vec![b'x'; 100_000]);
+
+    assert_eq!(code, Some(0));
+    assert_eq!(String::from_utf8_lossy(&output), "read 100000 bytes");
+}
+
+#[test]
+fn relays_to_the_backend_and_back() {
+    let backend = start_echo_backend();
+    let (_server, listen) = Server::start(&backend.to_string());
+
+    let input: Vec<u8> = (0..1_000_000_u32).map(|i| i as u8).collect();
+    let (code, output) = run_client(listen, input.clone());
+
+    assert_eq!(code, Some(0));
+    assert!(
+        output == input,
+        "echoed {} of {} bytes",
+        output.len(),
+        input.len()
+    );
+}
+
+#[test]
+fn exits_with_backend_unreachable() {
+    // take a port nothing listens on
+    let backend = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (_server, listen) = Server::start(&backend.to_string());
+
+    let (code, _) = run_client(listen, b"hello".to_vec());
+
+    assert_eq!(code, Some(70));
+}
+
+#[test]
+fn exits_when_the_backend_closes() {
+    let backend = start_fixed_backend(b"fixed response");
+    let (_server, listen) = Server::start(&backend.to_string());
+
+    let mut child = Command::new(BIN)
+        .args(["client", &format!("quic://{}", listen)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // keep stdin open, as ssh does until it sees the end of the connection
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"request").unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while child.try_wait().unwrap().is_none() {
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("the client didn't exit after the backend closed");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"fixed response");
+}
+
+#[test]
+fn closes_the_backend_when_stdout_goes_away() {
+    // an echo backend reporting when its connection ends
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = listener.local_addr().unwrap();
+    let (closed, backend_closed) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 16384];
+        while let Ok(n @ 1..) = stream.read(&mut buf) {
+            if stream.write_all(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        closed.send(()).unwrap();
+    });
+    let (_server, listen) = Server::start(&backend.to_string());
+
+    let mut child = Command::new(BIN)
+        .args(["client", &format!("quic://{}", listen)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // enough data in flight to fill the congestion window, with stdin kept open
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || {
+        let _ = stdin.write_all(&vec![0x55; 2_000_000]);
+        thread::sleep(Duration::from_secs(10));
+    });
+    let mut stdout = child.stdout.take().unwrap();
+    stdout.read_exact(&mut [0; 100]).unwrap();
+    drop(stdout);
+
+    let closed = backend_closed.recv_timeout(Duration::from_secs(5));
+    let _ = child.kill();
+    let _ = child.wait();
+    assert!(closed.is_ok(), "the backend connection was left open");
+}
+
+#[test]
+fn exits_when_the_max_duration_is_reached() {
+    let backend = start_echo_backend();
+    let (_server, listen) = Server::start_with(&backend.to_string(), &["--max-duration", "1"]);
+
+    let mut child = Command::new(BIN)
+        .args(["client", &format!("quic://{}", listen)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // an active session, which the server ends anyway
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"request").unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while child.try_wait().unwrap().is_none() {
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("the client didn't exit after the max duration");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(stdin);
+
+    assert_eq!(child.wait().unwrap().code(), Some(73));
+}
+
+#[test]
+fn appends_an_audit_entry_per_connection() {
+    let backend = start_echo_backend();
+    let log = std::env::temp_dir().join(format!("quicssh-test-{}.audit", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let (_server, listen) = Server::start_with(
+        &backend.to_string(),
+        &["--audit-log", log.to_str().unwrap()],
+    );
+
+    let (code, _) = run_client(listen, b"request".to_vec());
+    assert_eq!(code, Some(0));
+
+    // the entry is written once the server sees the close
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let entries = loop {
+        let entries = std::fs::read_to_string(&log).unwrap_or_default();
+        if !entries.is_empty() || Instant::now() > deadline {
+            break entries;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let _ = std::fs::remove_file(&log);
+
+    assert_eq!(entries.lines().count(), 1, "{}", entries);
+    assert!(
+        entries.contains(&format!(" backend={} ", backend)),
+        "{}",
+        entries
+    );
+    // the bytes relayed to and from the backend, not those of the QUIC packets
+    assert!(entries.contains(" bytes_in=7 bytes_out=7 "), "{}", entries);
+    assert!(entries.contains(" closed_by=client code=0 "), "{}", entries);
+}
+
+#[test]
+fn routes_by_the_route_header_behind_a_decoy_sni() {
+    let backend = start_echo_backend();
+    let conf = std::env::temp_dir().join(format!("quicssh-test-{}.toml", std::process::id()));
+    std::fs::write(&conf, format!("[proxy]\n\"real.test\" = \"{}\"\n", backend)).unwrap();
+    // anything not routed to the backend fails to connect
+    let (_server, listen) = Server::start_with("127.0.0.1:1", &["--conf", conf.to_str().unwrap()]);
+    let _ = std::fs::remove_file(&conf);
+
+    let fixed = [
+        "--sni",
+        "real.test",
+        "--sni-mode",
+        "fixed",
+        "--sni-decoy",
+        "decoy.test",
+    ];
+    let (code, output) = run_client_with(listen, &fixed, b"request".to_vec());
+    assert_eq!(code, Some(0));
+    assert_eq!(output, b"request");
+
+    // without an SNI the server routes as for any client
+    let none = ["--sni", "real.test", "--sni-mode", "none"];
+    let (code, _) = run_client_with(listen, &none, b"request".to_vec());
+    assert_eq!(code, Some(70));
+}
+
+#[test]
+fn routes_by_the_common_name_of_the_client_certificate() {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType};
+    use rcgen::{ExtendedKeyUsagePurpose, IsCa};
+
+    let mut ca = CertificateParams::new(Vec::new());
+    ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca).unwrap();
+    let mut client = CertificateParams::new(Vec::new());
+    client.distinguished_name.push(DnType::CommonName, "alice");
+    client.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client = Certificate::from_params(client).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("quicssh-test-{}-cn", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: String| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let ca_path = write("ca.pem", ca.serialize_pem().unwrap());
+    let cert_path = write("client.pem", client.serialize_pem_with_signer(&ca).unwrap());
+    let key_path = write("client.key", client.serialize_private_key_pem());
+    let backend = start_echo_backend();
+    let conf = write(
+        "server.toml",
+        format!("[proxy-by-cn]\nalice = \"{}\"\n", backend),
+    );
+    // anything not routed to the backend fails to connect
+    let (_server, listen) =
+        Server::start_with("127.0.0.1:1", &["--conf", &conf, "--client-ca", &ca_path]);
+
+    let args = ["--client-cert", &cert_path, "--client-key", &key_path];
+    let (code, output) = run_client_with(listen, &args, b"request".to_vec());
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(code, Some(0));
+    assert_eq!(output, b"request");
+}
+
+#[test]
+fn finds_the_conf_file_in_the_config_dir() {
+    let config_dir = std::env::temp_dir().join(format!("quicssh-test-{}", std::process::id()));
+    std::fs::create_dir_all(config_dir.join("quicssh")).unwrap();
+    std::fs::write(
+        config_dir.join("quicssh").join("server.toml"),
+        "[proxy]\n\"found.test\" = \"127.0.0.1:22\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new(BIN)
+        .args(["server", "--check-config"])
+        .env("XDG_CONFIG_HOME", &config_dir)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&config_dir);
+
+    assert!(output.status.success());
+    let routes = String::from_utf8(output.stdout).unwrap();
+    assert!(routes.contains("found.test -> 127.0.0.1:22"), "{}", routes);
+}
+
+#[test]
+fn both_sides_log_the_same_tls_secrets() {
+    let backend = start_echo_backend();
+    let dir = std::env::temp_dir();
+    let server_log = dir.join(format!("quicssh-test-{}.server-keys", std::process::id()));
+    let client_log = dir.join(format!("quicssh-test-{}.client-keys", std::process::id()));
+    let (_server, listen) = Server::start_with(
+        &backend.to_string(),
+        &["--keylog", server_log.to_str().unwrap()],
+    );
+
+    let keylog = ["--keylog", client_log.to_str().unwrap()];
+    let (code, _) = run_client_with(listen, &keylog, b"request".to_vec());
+    assert_eq!(code, Some(0));
+
+    let read_sorted = |path| {
+        let mut lines: Vec<String> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        let _ = std::fs::remove_file(path);
+        lines.sort();
+        lines
+    };
+    let client_keys = read_sorted(&client_log);
+    assert!(
+        client_keys
+            .iter()
+            .any(|line| line.starts_with("CLIENT_TRAFFIC_SECRET_0 ")),
+        "{:?}",
+        client_keys
+    );
+    assert_eq!(client_keys, read_sorted(&server_log));
+}
+
+#[test]
+fn names_the_family_of_a_socket_it_cannot_create() {
+    // a documentation address, which no interface has
+    let output = Command::new(BIN)
+        .args(["client", "--bind", "192.0.2.1:0", "quic://127.0.0.1:4433"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("creating IPv4 UDP socket bound to 192.0.2.1:0 failed"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn paces_the_relay_to_the_rate_limit() {
+    let backend = start_echo_backend();
+    let (_server, listen) = Server::start_with(&backend.to_string(), &["--rate-limit", "200000"]);
+
+    // 3 seconds worth of data at the limit, less the initial burst
+    let input = vec![0x55; 600_000];
+    let started = Instant::now();
+    let (code, output) = run_client(listen, input.clone());
+    let elapsed = started.elapsed();
+
+    assert_eq!(code, Some(0));
+    assert_eq!(output.len(), input.len());
+    let rate = input.len() as f64 / elapsed.as_secs_f64();
+    assert!(
+        (150_000.0..=210_000.0).contains(&rate),
+        "relayed at {:.0} bytes/s in {:?}",
+        rate,
+        elapsed
+    );
+}
+
+#[test]
+fn selftest_passes() {
+    let output = Command::new(BIN)
+        .arg("selftest")
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.starts_with("PASS: round trip in "), "{}", stdout);
+}
+
+#[cfg(unix)]
+#[test]
+fn shares_the_connection_through_a_control_socket() {
+    let backend = start_echo_backend();
+    // a second connection would be turned away
+    let (_server, listen) = Server::start_with(
+        &backend.to_string(),
+        &[
+            "--max-connections",
+            "1",
+            "--on-overload",
+            "reject",
+            "--max-streams",
+            "2",
+        ],
+    );
+    let socket = std::env::temp_dir().join(format!("quicssh-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let client = |input: Stdio| {
+        Command::new(BIN)
+            .args(["client", "--control-socket"])
+            .arg(&socket)
+            .arg(format!("quic://{}", listen))
+            .stdin(input)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap()
+    };
+
+    let mut master = client(Stdio::piped());
+    let mut master_stdin = master.stdin.take().unwrap();
+    master_stdin.write_all(b"master\n").unwrap();
+    // the echo shows the master is connected, and so listening on the socket
+    let mut echoed = String::new();
+    BufReader::new(master.stdout.as_mut().unwrap())
+        .read_line(&mut echoed)
+        .unwrap();
+    assert_eq!(echoed, "master\n");
+
+    let mut attached = client(Stdio::piped());
+    attached
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"attached")
+        .unwrap();
+    let output = attached.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"attached");
+
+    drop(master_stdin);
+    assert_eq!(master.wait().unwrap().code(), Some(0));
+    assert!(!socket.exists());
+}
+
+#[test]
+fn reports_an_unreachable_server_without_panicking() {
+    // a port nothing listens on, once the socket holding it is closed
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let output = Command::new(BIN)
+        .args(["client", "--connect-timeout", "1"])
+        .arg(format!("quic://127.0.0.1:{}", port))
+        .env("XDG_CONFIG_HOME", empty_config_dir())
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("failed to connect"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
@@ -0,0 +1,177 @@
+//! Embeds the client and the server through the library API.
+
+use quicssh_rs::{Client, Server};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+#[tokio::test]
+async fn tunnels_through_the_builders() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    // quinn can't report the port it got, so take a free one beforehand
+    let listen = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = Server::new(listen).route(move |sni| {
+        assert_eq!(sni, "localhost");
+        backend_addr.clone()
+    });
+    let server = tokio::spawn(async move {
+        server
+            .serve(async {
+                let _ = stopped.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let mut tunnel = Client::new(&format!("quic://127.0.0.1:{}", listen.port()))
+        .unwrap()
+        .sni("localhost")
+        .connect()
+        .await
+        .unwrap();
+    tunnel.write_all(b"hello over quic").await.unwrap();
+    tunnel.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    tunnel.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, b"hello over quic");
+    tunnel.close().await;
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn reuses_the_connections_of_a_pool() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let listen = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    // the server routes once per connection
+    let connections = Arc::new(AtomicUsize::new(0));
+    let routed = connections.clone();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = Server::new(listen).route(move |_| {
+        routed.fetch_add(1, Ordering::Relaxed);
+        backend_addr.clone()
+    });
+    let server = tokio::spawn(async move {
+        server
+            .serve(async {
+                let _ = stopped.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let pool = Client::new(&format!("quic://127.0.0.1:{}", listen.port()))
+        .unwrap()
+        .pool(1)
+        .unwrap()
+        .idle_ttl(Duration::from_millis(500));
+    for _ in 0..3 {
+        let mut tunnel = pool.connect().await.unwrap();
+        tunnel.write_all(b"hello again").await.unwrap();
+        tunnel.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        tunnel.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello again");
+        tunnel.close().await;
+    }
+    assert_eq!(connections.load(Ordering::Relaxed), 1);
+    assert_eq!(pool.idle(), 1);
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(pool.idle(), 0);
+
+    // a tunnel dropped without closing may be mid-transfer, its connection isn't kept
+    let mut tunnel = pool.connect().await.unwrap();
+    tunnel.write_all(b"abandoned").await.unwrap();
+    drop(tunnel);
+    assert_eq!(pool.idle(), 0);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn reuses_a_pooled_connection_left_unread() {
+    // sends a banner then more output than the client reads
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            tokio::spawn(async move {
+                stream.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+                while stream.write_all(&[0; 65536]).await.is_ok() {}
+            });
+        }
+    });
+
+    let listen = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let routed = connections.clone();
+    let (stop, stopped) = oneshot::channel::<()>();
+    // the server allows a single stream per connection by default
+    let server = Server::new(listen).route(move |_| {
+        routed.fetch_add(1, Ordering::Relaxed);
+        backend_addr.clone()
+    });
+    let server = tokio::spawn(async move {
+        server
+            .serve(async {
+                let _ = stopped.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let pool = Client::new(&format!("quic://127.0.0.1:{}", listen.port()))
+        .unwrap()
+        .pool(1)
+        .unwrap();
+    for _ in 0..2 {
+        let mut tunnel = tokio::time::timeout(Duration::from_secs(5), pool.connect())
+            .await
+            .expect("opening a stream on the pooled connection stalled")
+            .unwrap();
+        // the server only sees the stream once data comes on it
+        tunnel.write_all(b"SSH-2.0-client\r\n").await.unwrap();
+        let mut banner = [0; 14];
+        tunnel.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"SSH-2.0-test\r\n");
+        tunnel.close().await;
+    }
+    assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}